@@ -0,0 +1,191 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use jiff::tz::TimeZone;
+use jiff::{Timestamp, Zoned};
+use tokio::fs;
+use tracing::trace;
+
+use crate::image::OutputFormat;
+
+/// Saves a camera's snapshots locally as `<save_dir>/<token>/<timestamp>.jpg` (or `.png`), or
+/// where `save_dir` says if it's a template.
+pub(crate) struct Archive {
+    layout: Layout,
+    /// How many snapshots to keep in each directory, the oldest are removed first.
+    keep: Option<usize>,
+}
+
+enum Layout {
+    /// The camera's subdirectory of `save_dir`.
+    Dir(PathBuf),
+    /// `save_dir` with `{token}` substituted, and strftime specifiers still to be expanded with
+    /// the local capture time. Names a file if it ends in `.jpg` or `.png`, otherwise a directory.
+    /// A file that already exists, eg: from a template with second resolution and an interval
+    /// under a second, isn't overwritten, `_1`, `_2` and so on are appended to the name instead.
+    Template(String),
+}
+
+/// Whether `save_dir` is a template rather than a directory.
+fn is_template(save_dir: &str) -> bool {
+    save_dir.contains('%') || save_dir.contains("{token}")
+}
+
+/// Checks that `save_dir`'s strftime specifiers, if any, are valid.
+pub(crate) fn validate_save_dir(save_dir: &Path) -> Result<()> {
+    let save_dir = save_dir.to_string_lossy();
+    if is_template(&save_dir) {
+        jiff::fmt::strtime::format(save_dir.as_bytes(), &Zoned::now())
+            .context("invalid save_dir template")?;
+    }
+
+    Ok(())
+}
+
+impl Archive {
+    pub(crate) fn new(save_dir: &Path, token: &str, keep: Option<usize>) -> Self {
+        let template = save_dir.to_string_lossy();
+        let layout = if is_template(&template) {
+            Layout::Template(template.replace("{token}", token))
+        } else {
+            Layout::Dir(save_dir.join(token))
+        };
+
+        Self { layout, keep }
+    }
+
+    /// Saves a frame named by when it was `captured`.
+    pub(crate) async fn save(&self, frame: &[u8], captured: Timestamp) -> Result<()> {
+        let extension = OutputFormat::detect(frame).extension();
+        let path = unused(self.path(extension, captured)?).await;
+        let dir = path.parent().unwrap_or(Path::new("."));
+
+        fs::create_dir_all(dir)
+            .await
+            .with_context(|| format!("error creating {}", dir.display()))?;
+
+        trace!("saving snapshot to {}", path.display());
+        fs::write(&path, frame)
+            .await
+            .with_context(|| format!("error writing {}", path.display()))?;
+
+        if let Some(keep) = self.keep {
+            prune(dir, keep).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Where to save a snapshot `captured` then.
+    fn path(&self, extension: &str, captured: Timestamp) -> Result<PathBuf> {
+        // utc so that file names sort chronologically.
+        let name = || {
+            format!(
+                "{}.{}",
+                captured.strftime("%Y-%m-%dT%H-%M-%S%.3fZ"),
+                extension
+            )
+        };
+
+        match &self.layout {
+            Layout::Dir(dir) => Ok(dir.join(name())),
+            Layout::Template(template) => {
+                let mut path = PathBuf::from(
+                    jiff::fmt::strtime::format(
+                        template.as_bytes(),
+                        &captured.to_zoned(TimeZone::system()),
+                    )
+                    .context("invalid save_dir template")?,
+                );
+
+                if path
+                    .extension()
+                    .is_some_and(|ext| ext == "jpg" || ext == "png")
+                {
+                    // the frame's format wins over the template's.
+                    path.set_extension(extension);
+                } else {
+                    path.push(name());
+                }
+
+                Ok(path)
+            }
+        }
+    }
+}
+
+/// `path`, or if that exists the first of `<stem>_1.<ext>`, `<stem>_2.<ext>`... that doesn't, which
+/// sort after it.
+async fn unused(path: PathBuf) -> PathBuf {
+    let mut candidate = path.clone();
+    let mut suffix = 0;
+
+    while fs::try_exists(&candidate).await.unwrap_or(false) {
+        suffix += 1;
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let mut name = format!("{}_{}", stem, suffix);
+        if let Some(extension) = path.extension() {
+            name.push('.');
+            name.push_str(&extension.to_string_lossy());
+        }
+        candidate = path.with_file_name(name);
+    }
+
+    candidate
+}
+
+/// Removes the oldest snapshots in `dir` so that at most `keep` remain.
+async fn prune(dir: &Path, keep: usize) -> Result<()> {
+    let mut snapshots = Vec::new();
+
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path
+            .extension()
+            .is_some_and(|ext| ext == "jpg" || ext == "png")
+        {
+            snapshots.push(path);
+        }
+    }
+
+    if snapshots.len() <= keep {
+        return Ok(());
+    }
+
+    snapshots.sort();
+
+    for path in &snapshots[..snapshots.len() - keep] {
+        trace!("removing old snapshot {}", path.display());
+        fs::remove_file(path)
+            .await
+            .with_context(|| format!("error removing {}", path.display()))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn save_doesnt_overwrite_a_snapshot_from_the_same_second() {
+        let dir = std::env::temp_dir().join(format!("archive-test-{}", std::process::id()));
+        let archive = Archive::new(&dir.join("{token}/%H%M%S.jpg"), "camera", None);
+        let captured = Timestamp::now();
+
+        archive.save(b"first", captured).await.expect("save");
+        archive.save(b"second", captured).await.expect("save");
+
+        let path = archive.path("jpg", captured).expect("path");
+        let renamed = path.with_file_name(format!(
+            "{}_1.jpg",
+            path.file_stem().expect("stem").to_string_lossy()
+        ));
+        assert_eq!(fs::read(&path).await.expect("read"), b"first");
+        assert_eq!(fs::read(&renamed).await.expect("read"), b"second");
+
+        fs::remove_dir_all(&dir).await.expect("remove");
+    }
+}
@@ -0,0 +1,679 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use jiff::Timestamp;
+use reqwest::header::{HeaderMap, HeaderValue};
+use retina::client::SessionGroup;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
+use tracing::{debug, error, info, trace, warn};
+
+use crate::archive::Archive;
+use crate::motion::{MotionDetector, DEFAULT_MOTION_KEEPALIVE_SEC};
+use crate::mqtt::Mqtt;
+use crate::preview::{Preview, SnapshotReply, SnapshotRequests};
+use crate::prusalink::PrusaLink;
+use crate::s3::S3Uploader;
+use crate::snapshot::SnapshotFallback;
+use crate::stream::Stream;
+use crate::upload::{self, UploadLimits, Uploader};
+use crate::webhook::{FailureAlerts, FailureKind};
+use crate::{health, metrics, CameraConfig, Config};
+
+const SNAPSHOT_TIMEOUT_SEC: u64 = 30;
+
+/// The longest a failing camera waits between attempts, unless its interval is longer.
+const MAX_BACKOFF_SEC: u64 = 15 * 60;
+
+/// Logs a failure at `$level`, or at debug once the camera is backing off so that a dead camera
+/// doesn't flood the logs.
+macro_rules! failure {
+    ($camera:expr, $level:ident, $($arg:tt)+) => {
+        if $camera.backing_off() {
+            debug!($($arg)+)
+        } else {
+            $level!($($arg)+)
+        }
+    };
+}
+
+/// Everything needed to capture and upload snapshots from a single camera.
+pub(crate) struct Camera {
+    pub(crate) token: String,
+    pub(crate) stream: Stream,
+    /// Uploads to `upload_url`, unless disabled, followed by any `upload_targets`.
+    pub(crate) uploaders: Vec<Uploader>,
+    pub(crate) s3: Option<S3Uploader>,
+    pub(crate) mqtt: Option<Mqtt>,
+    pub(crate) fallback: Option<SnapshotFallback>,
+    pub(crate) archive: Option<Archive>,
+    /// Publishes each captured frame to the preview server.
+    pub(crate) preview: Option<watch::Sender<Option<Bytes>>>,
+    /// Records clips requested through the preview server, taken when the camera starts running.
+    pub(crate) clips: Option<ClipRecorder>,
+    /// Snapshots requested through the preview server or control socket.
+    pub(crate) snapshot_requests: mpsc::Receiver<SnapshotReply>,
+    /// Sends to `snapshot_requests`.
+    pub(crate) snapshots: SnapshotRequests,
+    pub(crate) alerts: Option<FailureAlerts>,
+    /// Skips frames that didn't change, every frame is published if unset.
+    pub(crate) motion: Option<MotionDetector>,
+    pub(crate) printer: Option<PrusaLink>,
+    /// Frames larger than this aren't uploaded.
+    pub(crate) max_jpeg_bytes: Option<usize>,
+    /// Waits for the next multiple of the interval since the epoch, rather than the interval.
+    pub(crate) align_to_clock: bool,
+    /// Logs frames instead of uploading or publishing them.
+    pub(crate) dry_run: bool,
+    /// Failures in a row after which attempts are backed off, never if unset.
+    pub(crate) max_consecutive_failures: Option<u32>,
+    pub(crate) consecutive_failures: u32,
+}
+
+impl Camera {
+    /// Builds `camera` from the config, registering it with `preview` if set.
+    pub(crate) fn new(
+        config: &Config,
+        camera: &CameraConfig,
+        client: &Arc<reqwest::Client>,
+        session_group: &Arc<SessionGroup>,
+        preview: Option<&Preview>,
+        upload_limits: &UploadLimits,
+        mqtt: Option<&Mqtt>,
+    ) -> Result<Self> {
+        let stream =
+            Stream::new(camera, session_group.clone()).context("error constructing stream")?;
+
+        let upload_url = camera
+            .upload_url
+            .as_ref()
+            .unwrap_or(&config.upload_url)
+            .expose()
+            .to_string();
+        // prusa connect identifies the camera by its fingerprint, which defaults to the token.
+        let fingerprint = camera.fingerprint.as_ref().unwrap_or(&camera.token);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "fingerprint",
+            HeaderValue::from_str(fingerprint)
+                .with_context(|| format!("camera {}: invalid fingerprint", camera.token))?,
+        );
+        // the camera's own headers replace any with the same name.
+        headers.extend(
+            upload::parse_headers(&config.upload_headers).context("invalid upload_headers")?,
+        );
+        headers.extend(
+            upload::parse_headers(&camera.upload_headers)
+                .with_context(|| format!("camera {}: invalid upload_headers", camera.token))?,
+        );
+        let upload_timeout = Duration::from_secs(config.upload_timeout_sec);
+        let uploader = |url: String, headers: HeaderMap| {
+            Uploader::new(
+                client.clone(),
+                url,
+                camera.token.clone(),
+                config.upload_max_retries,
+                upload_timeout,
+                headers,
+                upload_limits.clone(),
+            )
+        };
+        let mut uploaders = Vec::new();
+        for target in &camera.upload_targets {
+            let mut target_headers = headers.clone();
+            target_headers.extend(upload::parse_headers(&target.headers).with_context(|| {
+                format!("camera {}: invalid upload_targets headers", camera.token)
+            })?);
+            uploaders.push(uploader(target.url.expose().to_string(), target_headers));
+        }
+        if config.upload_enabled {
+            uploaders.insert(0, uploader(upload_url, headers));
+        }
+        let s3 = config.s3.clone().map(|s3| {
+            S3Uploader::new(
+                client.clone(),
+                s3,
+                camera.token.clone(),
+                upload_timeout,
+                upload_limits.clone(),
+            )
+        });
+        let fallback = SnapshotFallback::new(client.clone(), camera);
+        let archive = config
+            .save_dir
+            .as_ref()
+            .map(|dir| Archive::new(dir, &camera.token, config.save_keep));
+        let alerts = config
+            .failure_webhook
+            .as_ref()
+            .filter(|_| !config.dry_run)
+            .map(|url| {
+                FailureAlerts::new(
+                    client.clone(),
+                    url.expose().to_string(),
+                    config.failure_threshold,
+                )
+            });
+        let motion = (camera.motion_threshold.is_some() || camera.skip_duplicates).then(|| {
+            let keepalive = camera
+                .motion_keepalive_sec
+                .unwrap_or(DEFAULT_MOTION_KEEPALIVE_SEC);
+            MotionDetector::new(camera.motion_threshold, Duration::from_secs(keepalive))
+        });
+        let printer = camera
+            .prusalink
+            .as_ref()
+            .map(|prusalink| PrusaLink::new(client.clone(), prusalink));
+        // a request arriving mid-recording is rejected rather than queued.
+        let (clips, recorder) = match (preview, &config.clip_dir) {
+            (Some(_), Some(clip_dir)) => {
+                let (tx, rx) = mpsc::channel(1);
+                let recorder = ClipRecorder::new(camera, session_group, clip_dir, rx)?;
+                (Some(tx), Some(recorder))
+            }
+            _ => (None, None),
+        };
+        let (snapshots, snapshot_requests) = mpsc::channel(1);
+        let preview = preview.map(|preview| {
+            let (tx, rx) = watch::channel(None);
+            preview.insert(camera.token.clone(), rx, clips, snapshots.clone());
+            tx
+        });
+
+        Ok(Self {
+            token: camera.token.clone(),
+            stream,
+            uploaders,
+            s3,
+            mqtt: mqtt.cloned(),
+            fallback,
+            archive,
+            preview,
+            clips: recorder,
+            snapshot_requests,
+            snapshots,
+            alerts,
+            motion,
+            printer,
+            max_jpeg_bytes: camera.max_jpeg_bytes,
+            align_to_clock: camera.align_to_clock,
+            dry_run: config.dry_run,
+            max_consecutive_failures: camera.max_consecutive_failures,
+            consecutive_failures: 0,
+        })
+    }
+
+    /// Captures and uploads a frame every `interval` until `shutdown` is set, or every
+    /// `idle_interval` while the camera's printer is idle. Nothing is captured while `paused` is
+    /// set, except for requested snapshots.
+    ///
+    /// An in-progress capture is abandoned on shutdown, but an in-progress upload is allowed to
+    /// finish.
+    pub(crate) async fn run(
+        mut self,
+        interval: Duration,
+        mut shutdown: watch::Receiver<bool>,
+        mut paused: watch::Receiver<bool>,
+    ) {
+        // recorded alongside the snapshots, and aborted when the camera stops.
+        let mut recorder = JoinSet::new();
+        if let Some(clips) = self.clips.take() {
+            recorder.spawn(clips.run());
+        }
+
+        metrics::register_camera(&self.token);
+        let mut health_interval = self.health_interval(Some(interval));
+        health::register_camera(&self.token, health_interval);
+
+        loop {
+            let current = match &mut self.printer {
+                _ if *paused.borrow_and_update() => None,
+                Some(printer) => tokio::select! {
+                    _ = shutdown.changed() => break,
+                    printing = printer.printing(&self.token) => {
+                        if printing { Some(interval) } else { printer.idle_interval() }
+                    }
+                },
+                None => Some(interval),
+            };
+
+            if self.health_interval(current) != health_interval {
+                health_interval = self.health_interval(current);
+                health::set_interval(&self.token, health_interval);
+            }
+
+            // paused, or while the printer is idle.
+            let Some(interval) = current else {
+                let poll_interval = self.printer.as_ref().map(PrusaLink::poll_interval);
+                tokio::select! {
+                    _ = shutdown.changed() => break,
+                    _ = tokio::time::sleep(poll_interval.unwrap_or(interval)) => continue,
+                    Ok(()) = paused.changed() => continue,
+                    Some(reply) = self.snapshot_requests.recv() => {
+                        let _ = reply.send(self.snapshot().await);
+                        continue;
+                    }
+                }
+            };
+
+            let frame = tokio::select! {
+                _ = shutdown.changed() => break,
+                frame = self.capture() => frame,
+            };
+
+            let failure = match frame {
+                Some((frame, _)) if self.skip(&frame) => None,
+                Some((frame, captured)) => match self
+                    .publish(frame, captured)
+                    .await
+                    .with_context(|| format!("camera {}", self.token))
+                {
+                    Ok(()) => {
+                        if let Some(motion) = &mut self.motion {
+                            motion.commit();
+                        }
+                        None
+                    }
+                    Err(e) => {
+                        failure!(self, error, err=?e, "error uploading frame: {:?}", e);
+                        health::record_error(&self.token, &e);
+                        Some(FailureKind::Upload)
+                    }
+                },
+                None => Some(FailureKind::Capture),
+            };
+
+            if let Some(alerts) = &mut self.alerts {
+                alerts.record(&self.token, failure);
+            }
+
+            let delay = match self.record_failure(failure.is_some(), interval) {
+                // not backing off.
+                delay if self.align_to_clock && delay == interval => until_aligned(interval),
+                delay => delay,
+            };
+
+            trace!("sleeping for {:?}", delay);
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                _ = tokio::time::sleep(delay) => {}
+                _ = self.stream.keepalive() => {}
+                Ok(()) = paused.changed() => {}
+                Some(reply) = self.snapshot_requests.recv() => {
+                    let _ = reply.send(self.snapshot().await);
+                }
+            }
+        }
+
+        debug!("stopped camera {}", self.token);
+    }
+
+    /// How often the camera is expected to upload when capturing every `interval`, a static scene
+    /// is only published every keepalive and a paused camera (`None`) isn't expected to at all.
+    fn health_interval(&self, interval: Option<Duration>) -> Duration {
+        match (interval, &self.motion) {
+            (None, _) => Duration::MAX,
+            (Some(interval), Some(motion)) => interval.max(motion.keepalive()),
+            (Some(interval), None) => interval,
+        }
+    }
+
+    fn backing_off(&self) -> bool {
+        self.max_consecutive_failures
+            .is_some_and(|max| self.consecutive_failures >= max)
+    }
+
+    /// Counts consecutive failures, returning how long to wait before the next attempt: `interval`,
+    /// doubling per failure past `max_consecutive_failures` up to `MAX_BACKOFF_SEC`.
+    fn record_failure(&mut self, failed: bool, interval: Duration) -> Duration {
+        if !failed {
+            if self.backing_off() {
+                info!("camera {} recovered", self.token);
+            }
+            self.consecutive_failures = 0;
+            health::set_consecutive_failures(&self.token, 0);
+            return interval;
+        }
+
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        health::set_consecutive_failures(&self.token, self.consecutive_failures);
+
+        let Some(max) = self.max_consecutive_failures else {
+            return interval;
+        };
+
+        if self.consecutive_failures == max {
+            warn!(
+                "camera {} failed {} times in a row, backing off until it recovers",
+                self.token, max
+            );
+        }
+
+        match self.consecutive_failures.checked_sub(max) {
+            Some(excess) => interval
+                .saturating_mul(2u32.saturating_pow(excess))
+                .min(Duration::from_secs(MAX_BACKOFF_SEC).max(interval)),
+            None => interval,
+        }
+    }
+
+    /// Captures and uploads a single frame.
+    pub(crate) async fn once(mut self) -> Result<()> {
+        metrics::register_camera(&self.token);
+
+        let (frame, captured) = self
+            .capture()
+            .await
+            .with_context(|| self.context("unable to capture frame"))?;
+
+        self.publish(frame, captured)
+            .await
+            .with_context(|| self.context("error uploading frame"))
+    }
+
+    /// Captures and uploads a frame requested through the preview server, however little it
+    /// changed, returning its size.
+    async fn snapshot(&mut self) -> Result<usize> {
+        debug!("snapshot requested for camera {}", self.token);
+
+        let (frame, captured) = self
+            .capture()
+            .await
+            .with_context(|| self.context("unable to capture frame"))?;
+        let bytes = frame.len();
+
+        self.publish(frame, captured)
+            .await
+            .with_context(|| self.context("error uploading frame"))?;
+
+        Ok(bytes)
+    }
+
+    /// Prefixes `what` with the camera, so errors from `once` and `snapshot` identify it.
+    fn context(&self, what: &str) -> String {
+        format!("camera {}: {}", self.token, what)
+    }
+
+    /// Captures a frame from the stream, or from the fallback snapshot url if that fails, with when
+    /// it was captured.
+    async fn capture(&mut self) -> Option<(Bytes, Timestamp)> {
+        let snapshot_timeout = Duration::from_secs(SNAPSHOT_TIMEOUT_SEC);
+        let token = &self.token;
+
+        // the stream applies its own connect and frame timeouts.
+        debug!("polling for frame from camera {}", token);
+        let frame = match self
+            .stream
+            .next()
+            .await
+            .with_context(|| format!("camera {}", token))
+        {
+            Ok(frame) => Some(frame),
+            Err(e) => {
+                failure!(self, error, err=?e, "error retrieving frame: {:?}", e);
+                health::record_error(token, &e);
+                None
+            }
+        };
+
+        let (frame, captured) = match (frame, &self.fallback) {
+            (None, Some(fallback)) => {
+                debug!("fetching fallback snapshot for camera {}", token);
+                match tokio::time::timeout(snapshot_timeout, fallback.fetch()).await {
+                    Ok(Ok(frame)) => Some((frame, Timestamp::now())),
+                    Ok(Err(e)) => {
+                        let e = e.context(format!("camera {}", token));
+                        failure!(self, error, err=?e, "error fetching fallback snapshot: {:?}", e);
+                        health::record_error(token, &e);
+                        None
+                    }
+                    Err(e) => {
+                        failure!(
+                            self,
+                            warn,
+                            "timeout waiting for fallback snapshot after {}",
+                            e
+                        );
+                        metrics::TIMEOUTS.with_label_values(&[token]).inc();
+                        health::record_error(
+                            token,
+                            &anyhow::anyhow!(
+                                "camera {}: timeout waiting for fallback snapshot after {}",
+                                token,
+                                e
+                            ),
+                        );
+                        None
+                    }
+                }
+            }
+            (frame, _) => frame,
+        }?;
+
+        metrics::FRAMES_CAPTURED.with_label_values(&[token]).inc();
+        metrics::JPEG_BYTES
+            .with_label_values(&[token])
+            .observe(frame.len() as f64);
+        health::record_capture(token, frame.len());
+
+        Some((frame, captured))
+    }
+
+    /// Whether `frame` didn't change, or barely changed, since the last successfully published
+    /// frame, in which case it's only shown in the preview.
+    fn skip(&mut self, frame: &Bytes) -> bool {
+        let Some(motion) = &mut self.motion else {
+            return false;
+        };
+
+        if motion.changed(frame) {
+            return false;
+        }
+
+        debug!("skipping unchanged frame from camera {}", self.token);
+        metrics::FRAMES_SKIPPED
+            .with_label_values(&[&self.token])
+            .inc();
+
+        if let Some(preview) = &self.preview {
+            preview.send_replace(Some(frame.clone()));
+        }
+
+        true
+    }
+
+    /// Publishes a frame to the preview server and archive, which names it by when it was
+    /// `captured`, then uploads it unless it exceeds `max_jpeg_bytes` or this is a dry run.
+    ///
+    /// S3 errors are only logged when also uploading to `upload_url` or `upload_targets`, as are
+    /// errors from some of those when others succeeded.
+    async fn publish(&self, frame: Bytes, captured: Timestamp) -> Result<()> {
+        let token = &self.token;
+
+        if let Some(preview) = &self.preview {
+            preview.send_replace(Some(frame.clone()));
+        }
+
+        if let Some(archive) = &self.archive {
+            if let Err(e) = archive.save(&frame, captured).await {
+                warn!(err=?e, "error saving snapshot for camera {}: {:?}", token, e)
+            }
+        }
+
+        if self.dry_run {
+            info!(
+                "dry run, not uploading {} byte frame from camera {}",
+                frame.len(),
+                token
+            );
+            // so that readiness reflects whether frames are captured, without counting an upload.
+            health::record_upload(token);
+            return Ok(());
+        }
+
+        if let Some(mqtt) = &self.mqtt {
+            if let Err(e) = mqtt.publish(token, frame.clone()) {
+                warn!(err=?e, "error publishing snapshot for camera {}: {:?}", token, e)
+            }
+        }
+
+        if let Some(max) = self.max_jpeg_bytes.filter(|max| frame.len() > *max) {
+            warn!(
+                "not uploading {} byte frame from camera {}, it exceeds max_jpeg_bytes of {}",
+                frame.len(),
+                token,
+                max
+            );
+            return Ok(());
+        }
+
+        if let Some(s3) = &self.s3 {
+            debug!("uploading image for camera {} to s3", token);
+            let result = s3.upload(frame.clone()).await;
+
+            match result {
+                result if self.uploaders.is_empty() => return self.record_upload(result),
+                Err(e) => {
+                    warn!(err=?e, "error uploading snapshot for camera {} to s3: {:?}", token, e)
+                }
+                Ok(()) => {}
+            }
+        }
+
+        if self.uploaders.is_empty() {
+            return Ok(());
+        }
+
+        debug!("uploading image for camera {}", token);
+        let results = futures_util::future::join_all(
+            self.uploaders
+                .iter()
+                .map(|uploader| uploader.upload(frame.clone())),
+        )
+        .await;
+
+        let mut errors = Vec::new();
+        for (uploader, result) in self.uploaders.iter().zip(results) {
+            let target = uploader.target();
+            match result {
+                Ok(()) => metrics::TARGET_UPLOADS_SUCCEEDED
+                    .with_label_values(&[token, &target])
+                    .inc(),
+                Err(e) => {
+                    metrics::TARGET_UPLOADS_FAILED
+                        .with_label_values(&[token, &target])
+                        .inc();
+                    errors.push((target, e));
+                }
+            }
+        }
+
+        // the frame was uploaded as long as one target succeeded, the first error is returned
+        // if none did.
+        let result = if errors.len() < self.uploaders.len() {
+            Ok(())
+        } else {
+            Err(errors.remove(0).1)
+        };
+        for (target, e) in errors {
+            warn!(err=?e, "error uploading snapshot for camera {} to {}: {:?}", token, target, e)
+        }
+
+        self.record_upload(result)
+    }
+
+    fn record_upload(&self, result: Result<()>) -> Result<()> {
+        let token = &self.token;
+
+        match result {
+            Ok(()) => {
+                metrics::record_upload(token);
+                health::record_upload(token);
+                Ok(())
+            }
+            Err(e) => {
+                metrics::UPLOADS_FAILED.with_label_values(&[token]).inc();
+                Err(e)
+            }
+        }
+    }
+}
+
+/// How long until the next multiple of `interval` since the Unix epoch, so that however long a
+/// capture took the next one starts on time.
+fn until_aligned(interval: Duration) -> Duration {
+    let since_epoch = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let interval_nanos = interval.as_nanos().max(1);
+
+    let remaining = interval_nanos - since_epoch % interval_nanos;
+    Duration::from_nanos(u64::try_from(remaining).unwrap_or(u64::MAX))
+}
+
+/// Records clips on a session of its own, so that the camera keeps capturing meanwhile.
+pub(crate) struct ClipRecorder {
+    token: String,
+    stream: Stream,
+    /// Clips are saved here, in a subdirectory per camera.
+    clip_dir: PathBuf,
+    requests: mpsc::Receiver<Duration>,
+}
+
+impl ClipRecorder {
+    fn new(
+        camera: &CameraConfig,
+        session_group: &Arc<SessionGroup>,
+        clip_dir: &Path,
+        requests: mpsc::Receiver<Duration>,
+    ) -> Result<Self> {
+        // the camera's own stream writes the dump.
+        let config = CameraConfig {
+            dump_h264_path: None,
+            ..camera.clone()
+        };
+
+        Ok(Self {
+            token: camera.token.clone(),
+            stream: Stream::new(&config, session_group.clone())
+                .context("error constructing clip stream")?,
+            clip_dir: clip_dir.to_path_buf(),
+            requests,
+        })
+    }
+
+    /// Records each requested clip, until the camera stops.
+    async fn run(mut self) {
+        while let Some(duration) = self.requests.recv().await {
+            self.record(duration).await;
+        }
+    }
+
+    /// Records a clip of `duration` into `clip_dir`, logging any error.
+    async fn record(&mut self, duration: Duration) {
+        let dir = self.clip_dir.join(&self.token);
+        // utc so that file names sort chronologically, matching the archive.
+        let name = Timestamp::now().strftime("%Y-%m-%dT%H-%M-%S%.3fZ");
+        let path = dir.join(format!("{}.h264", name));
+
+        if let Err(e) = tokio::fs::create_dir_all(&dir).await {
+            error!(err=?e, "error creating {}: {:?}", dir.display(), e);
+            return;
+        }
+
+        info!(
+            "recording {:?} clip from camera {} to {}",
+            duration,
+            self.token,
+            path.display()
+        );
+        match self.stream.record(path.clone(), duration).await {
+            Ok(()) => info!("saved clip {}", path.display()),
+            Err(e) => error!(err=?e, "error recording clip from camera {}: {:?}", self.token, e),
+        }
+    }
+}
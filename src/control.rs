@@ -0,0 +1,139 @@
+//! Serves `control_socket`, a unix socket taking one command per line from local scripts:
+//! `snapshot <token>`, `reload`, `status`, `pause <token>` and `resume <token>`.
+//!
+//! Each command is answered with a line starting with "ok" or "error:", except `status`, which is
+//! answered with the `/cameras` JSON. Anyone who can connect to the socket can run commands, so
+//! access is controlled by the permissions of its directory.
+
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, warn};
+
+use crate::preview::{self, SnapshotError, SnapshotRequests};
+
+/// A command read from the socket.
+#[derive(Debug)]
+pub(crate) enum Command {
+    Snapshot(String),
+    Reload,
+    Status,
+    Pause(String),
+    Resume(String),
+}
+
+/// A command and where to send the line answering it.
+pub(crate) struct Request {
+    pub(crate) command: Command,
+    pub(crate) reply: oneshot::Sender<String>,
+}
+
+impl Command {
+    fn parse(line: &str) -> Result<Self> {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        let token = words.next().map(str::to_string);
+
+        let command = match (command, token) {
+            ("snapshot", Some(token)) => Command::Snapshot(token),
+            ("pause", Some(token)) => Command::Pause(token),
+            ("resume", Some(token)) => Command::Resume(token),
+            ("reload", None) => Command::Reload,
+            ("status", None) => Command::Status,
+            ("snapshot" | "pause" | "resume", None) => bail!("{} needs a camera token", command),
+            _ => bail!(
+                "unknown command {:?}, expected snapshot <token>, reload, status, pause <token> or resume <token>",
+                line
+            ),
+        };
+
+        if words.next().is_some() {
+            bail!("too many arguments in {:?}", line);
+        }
+
+        Ok(command)
+    }
+}
+
+/// Listens on `path`, replacing a socket left behind by a previous run. Anything else already at
+/// `path` is left alone, eg: a config file given by mistake.
+pub(crate) fn bind(path: &Path) -> Result<UnixListener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)
+            .with_context(|| format!("unable to remove {}", path.display()))?,
+        Ok(_) => bail!("{} already exists and isn't a socket", path.display()),
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            return Err(e).with_context(|| format!("unable to read {}", path.display()))
+        }
+        Err(_) => {}
+    }
+
+    UnixListener::bind(path).with_context(|| format!("unable to bind {}", path.display()))
+}
+
+/// Accepts connections, forwarding each command to `requests` and writing back the answer.
+pub(crate) async fn serve(listener: UnixListener, requests: mpsc::Sender<Request>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(handle(stream, requests.clone()));
+            }
+            Err(e) => warn!(err=?e, "error accepting control connection: {:?}", e),
+        }
+    }
+}
+
+async fn handle(stream: UnixStream, requests: mpsc::Sender<Request>) {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        debug!("control command: {}", line);
+        let answer = match Command::parse(line) {
+            Ok(command) => request(&requests, command).await,
+            Err(e) => format!("error: {:#}", e),
+        };
+
+        if write
+            .write_all(format!("{}\n", answer).as_bytes())
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+async fn request(requests: &mpsc::Sender<Request>, command: Command) -> String {
+    let (reply, answer) = oneshot::channel();
+
+    if requests.send(Request { command, reply }).await.is_err() {
+        return "error: shutting down".to_string();
+    }
+
+    answer
+        .await
+        .unwrap_or_else(|_| "error: shutting down".to_string())
+}
+
+/// Answers a `snapshot` command once the camera has uploaded it.
+pub(crate) async fn snapshot(snapshots: SnapshotRequests) -> String {
+    match preview::request_snapshot(&snapshots).await {
+        Ok(bytes) => format!("ok uploaded {} byte snapshot", bytes),
+        Err(SnapshotError::Pending) => "error: a snapshot is already pending".to_string(),
+        Err(SnapshotError::Stopped | SnapshotError::Abandoned) => {
+            "error: the camera stopped".to_string()
+        }
+        Err(SnapshotError::Failed(e)) => format!("error: {:#}", e),
+    }
+}
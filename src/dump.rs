@@ -0,0 +1,227 @@
+//! Writes a camera's raw H.264 bitstream to a file, eg: to reproduce a decoding bug or as a clip.
+//!
+//! The file is written on a thread of its own, so that a slow disk doesn't block the runtime.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, Write};
+use std::path::PathBuf;
+
+use tokio::sync::{mpsc, oneshot};
+use tracing::{info, warn};
+
+use crate::stream::avcc_to_annex_b_iterator;
+
+pub(crate) const DEFAULT_DUMP_MAX_BYTES: u64 = 64 * 1024 * 1024;
+
+const START_CODE: [u8; 4] = [0, 0, 0, 1];
+
+/// Frames queued for the writer thread before writing waits for it to catch up.
+const QUEUED_FRAMES: usize = 32;
+
+enum Message {
+    /// An AVCDecoderConfigurationRecord.
+    Parameters(Vec<u8>),
+    /// A frame and its nal length size.
+    Frame(Vec<u8>, usize),
+}
+
+/// Appends annex b nal units to a file, starting over once it reaches `max_bytes` so that it holds
+/// the most recent part of the stream.
+pub(crate) struct BitstreamDump {
+    messages: mpsc::Sender<Message>,
+    /// Whether every write succeeded, sent once the writer thread stops.
+    finished: oneshot::Receiver<bool>,
+    /// The last decoder configuration record sent to the writer thread.
+    extra_data: Vec<u8>,
+}
+
+impl BitstreamDump {
+    pub(crate) fn new(path: PathBuf, max_bytes: u64) -> Self {
+        let (messages, mut receiver) = mpsc::channel(QUEUED_FRAMES);
+        let (done, finished) = oneshot::channel();
+
+        let mut writer = Writer {
+            path,
+            max_bytes,
+            file: None,
+            written: 0,
+            extra_data: Vec::new(),
+            failed: false,
+        };
+
+        let spawned = std::thread::Builder::new()
+            .name("bitstream-dump".to_string())
+            .spawn(move || {
+                // stops once the dump is dropped, or after the first error.
+                while let Some(message) = receiver.blocking_recv() {
+                    match message {
+                        Message::Parameters(extra_data) => writer.set_parameters(extra_data),
+                        Message::Frame(data, nal_length_size) => {
+                            writer.write_frame(&data, nal_length_size)
+                        }
+                    }
+
+                    if writer.failed {
+                        break;
+                    }
+                }
+
+                let _ = done.send(!writer.failed);
+            });
+        if let Err(e) = spawned {
+            warn!(err=?e, "unable to start writing bitstream dump: {:?}", e);
+        }
+
+        Self {
+            messages,
+            finished,
+            extra_data: Vec::new(),
+        }
+    }
+
+    /// Writes the SPS and PPS from an AVCDecoderConfigurationRecord, if they changed, so the dump
+    /// can be decoded from the next keyframe.
+    pub(crate) async fn set_parameters(&mut self, extra_data: &[u8]) {
+        if extra_data == self.extra_data {
+            return;
+        }
+
+        self.extra_data = extra_data.to_vec();
+        // fails once the writer has stopped after an error, which it logged.
+        let _ = self
+            .messages
+            .send(Message::Parameters(extra_data.to_vec()))
+            .await;
+    }
+
+    /// Writes a frame's nal units, each prefixed with a start code.
+    pub(crate) async fn write_frame(&mut self, data: Vec<u8>, nal_length_size: usize) {
+        let _ = self
+            .messages
+            .send(Message::Frame(data, nal_length_size))
+            .await;
+    }
+
+    /// Waits for everything sent to be written, returning whether it all was.
+    pub(crate) async fn finish(self) -> bool {
+        drop(self.messages);
+        self.finished.await.unwrap_or(false)
+    }
+}
+
+/// Writes a dump's file, on the dump's thread.
+struct Writer {
+    path: PathBuf,
+    max_bytes: u64,
+    /// `None` until the first write, and after an error.
+    file: Option<File>,
+    written: u64,
+    /// The last decoder configuration record, its parameter sets start every file.
+    extra_data: Vec<u8>,
+    failed: bool,
+}
+
+impl Writer {
+    fn set_parameters(&mut self, extra_data: Vec<u8>) {
+        let result = parameter_sets(&extra_data)
+            .into_iter()
+            .try_for_each(|nal| self.write(nal));
+        self.extra_data = extra_data;
+        self.check(result);
+    }
+
+    fn write_frame(&mut self, data: &[u8], nal_length_size: usize) {
+        if self.written >= self.max_bytes {
+            let result = self.restart();
+            self.check(result);
+        }
+
+        let result =
+            avcc_to_annex_b_iterator(data, nal_length_size).try_for_each(|nal| self.write(nal));
+        self.check(result);
+    }
+
+    /// Truncates the file, then writes the parameter sets again.
+    fn restart(&mut self) -> io::Result<()> {
+        info!(
+            "{} reached {} bytes, starting over",
+            self.path.display(),
+            self.max_bytes
+        );
+
+        if let Some(file) = &mut self.file {
+            file.set_len(0)?;
+            file.rewind()?;
+        }
+        self.written = 0;
+
+        let extra_data = std::mem::take(&mut self.extra_data);
+        let result = parameter_sets(&extra_data)
+            .into_iter()
+            .try_for_each(|nal| self.write(nal));
+        self.extra_data = extra_data;
+
+        result
+    }
+
+    fn write(&mut self, nal: &[u8]) -> io::Result<()> {
+        if self.failed {
+            return Ok(());
+        }
+
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => self.file.insert(
+                OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .truncate(true)
+                    .open(&self.path)?,
+            ),
+        };
+
+        file.write_all(&START_CODE)?;
+        file.write_all(nal)?;
+        self.written += (START_CODE.len() + nal.len()) as u64;
+
+        Ok(())
+    }
+
+    /// Stops writing after the first error, rather than logging one per frame.
+    fn check(&mut self, result: io::Result<()>) {
+        if let Err(e) = result {
+            warn!(err=?e, "error writing {}, no longer writing to it: {:?}", self.path.display(), e);
+            self.failed = true;
+            self.file = None;
+        }
+    }
+}
+
+/// The SPS and PPS nal units in an AVCDecoderConfigurationRecord, those that fit if it's truncated.
+fn parameter_sets(extra_data: &[u8]) -> Vec<&[u8]> {
+    let mut nals = Vec::new();
+    let mut rest = extra_data.get(5..).unwrap_or_default();
+
+    // the count of SPS is in the low 5 bits, the count of PPS is a whole byte.
+    for mask in [0x1f, 0xff] {
+        let Some((&count, after)) = rest.split_first() else {
+            break;
+        };
+        rest = after;
+
+        for _ in 0..count & mask {
+            let Some(length) = rest.get(..2) else {
+                return nals;
+            };
+            let length = u16::from_be_bytes([length[0], length[1]]) as usize;
+
+            let Some(nal) = rest.get(2..2 + length) else {
+                return nals;
+            };
+            nals.push(nal);
+            rest = &rest[2 + length..];
+        }
+    }
+
+    nals
+}
@@ -0,0 +1,164 @@
+//! Decoding via FFmpeg, of H.265 with the `hevc` feature and of H.264 with the `ffmpeg` feature,
+//! optionally on the GPU.
+
+use anyhow::{anyhow, bail, Context, Result};
+use ffmpeg::format::Pixel;
+use ffmpeg::software::scaling;
+use ffmpeg::util::frame::Video;
+use ffmpeg_next as ffmpeg;
+use openh264::formats::YUVSource;
+
+use crate::image::{DecodedFrame, YuvFrame};
+use crate::stream::{Codec, FrameDecoder, HwAccel};
+
+pub(crate) struct Decoder {
+    decoder: ffmpeg::decoder::Video,
+}
+
+impl Decoder {
+    pub(crate) fn new(codec: Codec) -> Result<Self> {
+        Self::with_context(codec, |_| Ok(()))
+    }
+
+    /// A decoder using `hwaccel`, failing if ffmpeg wasn't built with it or there's no device.
+    pub(crate) fn new_hardware(codec: Codec, hwaccel: HwAccel) -> Result<Self> {
+        let device_type = match hwaccel {
+            HwAccel::Vaapi => ffmpeg::ffi::AVHWDeviceType::AV_HWDEVICE_TYPE_VAAPI,
+            HwAccel::Nvdec => ffmpeg::ffi::AVHWDeviceType::AV_HWDEVICE_TYPE_CUDA,
+        };
+
+        Self::with_context(codec, |context| {
+            let mut device = std::ptr::null_mut();
+            // SAFETY: on success the context takes ownership of the device reference, and frees
+            // it along with itself.
+            unsafe {
+                let ret = ffmpeg::ffi::av_hwdevice_ctx_create(
+                    &mut device,
+                    device_type,
+                    std::ptr::null(),
+                    std::ptr::null_mut(),
+                    0,
+                );
+                if ret < 0 {
+                    return Err(anyhow!(ffmpeg::Error::from(ret))
+                        .context(format!("unable to open {:?} device", hwaccel)));
+                }
+                (*context.as_mut_ptr()).hw_device_ctx = device;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn with_context(
+        codec: Codec,
+        configure: impl FnOnce(&mut ffmpeg::codec::Context) -> Result<()>,
+    ) -> Result<Self> {
+        ffmpeg::init().context("unable to initialize ffmpeg")?;
+
+        let id = match codec {
+            Codec::H264 => ffmpeg::codec::Id::H264,
+            Codec::H265 => ffmpeg::codec::Id::HEVC,
+            Codec::Jpeg => bail!("jpeg frames aren't decoded"),
+        };
+
+        let codec = ffmpeg::decoder::find(id)
+            .ok_or_else(|| anyhow!("ffmpeg was built without a {:?} decoder", codec))?;
+
+        let mut context = ffmpeg::codec::Context::new_with_codec(codec);
+        configure(&mut context)?;
+        let decoder = context.decoder().video()?;
+
+        Ok(Self { decoder })
+    }
+
+    /// Decodes a complete annex b access unit, returning an 8 bit frame once one is available.
+    fn receive(&mut self, access_unit: &[u8]) -> Result<Option<Frame>> {
+        self.decoder
+            .send_packet(&ffmpeg::Packet::copy(access_unit))?;
+
+        let mut decoded = Video::empty();
+        match self.decoder.receive_frame(&mut decoded) {
+            Ok(()) => {}
+            Err(ffmpeg::Error::Other { errno }) if errno == ffmpeg::error::EAGAIN => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        if matches!(decoded.format(), Pixel::VAAPI | Pixel::CUDA) {
+            // download the surface, usually as nv12, which is converted below.
+            let mut downloaded = Video::empty();
+            // SAFETY: both frames are valid, the download is allocated by ffmpeg.
+            let ret = unsafe {
+                ffmpeg::ffi::av_hwframe_transfer_data(downloaded.as_mut_ptr(), decoded.as_ptr(), 0)
+            };
+            if ret < 0 {
+                return Err(anyhow!(ffmpeg::Error::from(ret)).context("error downloading frame"));
+            }
+            decoded = downloaded;
+        }
+
+        if matches!(
+            decoded.format(),
+            Pixel::YUV420P | Pixel::YUV422P | Pixel::YUV444P
+        ) {
+            return Ok(Some(Frame(decoded)));
+        }
+
+        // eg: 10 bit streams, convert to the 8 bit 4:2:0 layout to_jpeg expects.
+        let mut converted = Video::empty();
+        scaling::Context::get(
+            decoded.format(),
+            decoded.width(),
+            decoded.height(),
+            Pixel::YUV420P,
+            decoded.width(),
+            decoded.height(),
+            scaling::Flags::BILINEAR,
+        )?
+        .run(&decoded, &mut converted)?;
+
+        Ok(Some(Frame(converted)))
+    }
+}
+
+impl FrameDecoder for Decoder {
+    fn decode(&mut self, annexb: &[u8]) -> Result<Option<DecodedFrame<'_>>> {
+        Ok(self.receive(annexb)?.map(DecodedFrame::new))
+    }
+}
+
+/// A decoded 8 bit 4:2:0, 4:2:2 or 4:4:4 frame.
+struct Frame(Video);
+
+impl YuvFrame for Frame {
+    fn dimensions_uv(&self) -> (usize, usize) {
+        (
+            self.0.plane_width(1) as usize,
+            self.0.plane_height(1) as usize,
+        )
+    }
+}
+
+impl YUVSource for Frame {
+    fn dimensions(&self) -> (usize, usize) {
+        (self.0.width() as usize, self.0.height() as usize)
+    }
+
+    fn strides(&self) -> (usize, usize, usize) {
+        (self.0.stride(0), self.0.stride(1), self.0.stride(2))
+    }
+
+    fn y(&self) -> &[u8] {
+        self.0.data(0)
+    }
+
+    fn u(&self) -> &[u8] {
+        self.0.data(1)
+    }
+
+    fn v(&self) -> &[u8] {
+        self.0.data(2)
+    }
+}
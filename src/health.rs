@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use hyper::body::Incoming;
+use hyper::{Method, Request, Response, StatusCode};
+use jiff::Timestamp;
+use serde::Serialize;
+use tracing::{error, info, warn};
+
+use crate::server::{self, Body};
+use crate::{redact_url, CameraConfig};
+
+/// How many snapshot intervals a camera may go without uploading before it's unhealthy.
+const MAX_MISSED_INTERVALS: u32 = 3;
+
+struct CameraHealth {
+    interval: Duration,
+    /// When the camera started, or last stopped being paused, uploads are expected within a few
+    /// intervals of this too.
+    expected_since: Instant,
+    last_upload: Option<LastUpload>,
+    consecutive_failures: u32,
+    last_error: Option<String>,
+    last_jpeg_bytes: Option<usize>,
+    /// The stream's RFC 6381 codec, eg: "avc1.640028", and dimensions before any crop or scaling.
+    format: Option<(String, u32, u32)>,
+}
+
+struct LastUpload {
+    instant: Instant,
+    timestamp: Timestamp,
+}
+
+impl CameraHealth {
+    /// Whether the camera uploaded within the last few intervals, or isn't due to have yet. A
+    /// paused camera, eg: while its printer is idle, is always healthy.
+    fn healthy(&self) -> bool {
+        if self.interval == Duration::MAX {
+            return true;
+        }
+
+        let since = match &self.last_upload {
+            Some(last_upload) => last_upload.instant.max(self.expected_since),
+            None => self.expected_since,
+        };
+        since.elapsed() <= self.interval.saturating_mul(MAX_MISSED_INTERVALS)
+    }
+}
+
+/// The running cameras, by token.
+static CAMERAS: LazyLock<Mutex<HashMap<String, CameraHealth>>> = LazyLock::new(Default::default);
+
+/// Every camera in the config, including disabled ones, in config order.
+static CONFIGURED: LazyLock<Mutex<Vec<ConfiguredCamera>>> = LazyLock::new(Default::default);
+
+struct ConfiguredCamera {
+    token: String,
+    /// With any password masked.
+    url: String,
+    enabled: bool,
+}
+
+#[derive(Serialize)]
+struct Readiness<'a> {
+    unhealthy: Vec<&'a str>,
+}
+
+#[derive(Serialize)]
+struct Status<'a> {
+    token: &'a str,
+    healthy: bool,
+    /// RFC 3339, `None` if the camera hasn't uploaded yet.
+    last_upload: Option<String>,
+    seconds_since_upload: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct CameraStatus<'a> {
+    token: &'a str,
+    url: &'a str,
+    enabled: bool,
+    /// RFC 3339, `None` if the camera hasn't uploaded yet.
+    last_success: Option<String>,
+    consecutive_failures: u32,
+    last_error: Option<&'a str>,
+    last_jpeg_bytes: Option<usize>,
+    codec: Option<&'a str>,
+    width: Option<u32>,
+    height: Option<u32>,
+}
+
+/// Replaces the cameras listed by `/cameras`, on startup and when the config is reloaded.
+pub(crate) fn set_configured(cameras: &[CameraConfig]) {
+    *CONFIGURED.lock().expect("lock poisoned") = cameras
+        .iter()
+        .map(|camera| ConfiguredCamera {
+            token: camera.token.clone(),
+            url: redact_url(&camera.url()),
+            enabled: camera.enabled(),
+        })
+        .collect();
+}
+
+/// Starts tracking a camera that uploads every `interval`, or `Duration::MAX` if it's paused.
+pub(crate) fn register_camera(token: &str, interval: Duration) {
+    CAMERAS.lock().expect("lock poisoned").insert(
+        token.to_string(),
+        CameraHealth {
+            interval,
+            expected_since: Instant::now(),
+            last_upload: None,
+            consecutive_failures: 0,
+            last_error: None,
+            last_jpeg_bytes: None,
+            format: None,
+        },
+    );
+}
+
+/// Updates how often a camera is expected to upload, eg: while its printer is idle.
+pub(crate) fn set_interval(token: &str, interval: Duration) {
+    if let Some(camera) = CAMERAS.lock().expect("lock poisoned").get_mut(token) {
+        if camera.interval == Duration::MAX {
+            camera.expected_since = Instant::now();
+        }
+        camera.interval = interval;
+    }
+}
+
+/// Stops tracking a camera that has been removed from the config.
+pub(crate) fn unregister_camera(token: &str) {
+    CAMERAS.lock().expect("lock poisoned").remove(token);
+}
+
+pub(crate) fn record_upload(token: &str) {
+    if let Some(camera) = CAMERAS.lock().expect("lock poisoned").get_mut(token) {
+        camera.last_upload = Some(LastUpload {
+            instant: Instant::now(),
+            timestamp: Timestamp::now(),
+        });
+    }
+}
+
+pub(crate) fn record_capture(token: &str, jpeg_bytes: usize) {
+    if let Some(camera) = CAMERAS.lock().expect("lock poisoned").get_mut(token) {
+        camera.last_jpeg_bytes = Some(jpeg_bytes);
+    }
+}
+
+pub(crate) fn record_format(token: &str, codec: &str, width: u32, height: u32) {
+    if let Some(camera) = CAMERAS.lock().expect("lock poisoned").get_mut(token) {
+        camera.format = Some((codec.to_string(), width, height));
+    }
+}
+
+/// Records why a capture or upload failed, `/cameras` shows the most recent.
+pub(crate) fn record_error(token: &str, error: &anyhow::Error) {
+    if let Some(camera) = CAMERAS.lock().expect("lock poisoned").get_mut(token) {
+        camera.last_error = Some(format!("{:#}", error));
+    }
+}
+
+pub(crate) fn set_consecutive_failures(token: &str, consecutive_failures: u32) {
+    if let Some(camera) = CAMERAS.lock().expect("lock poisoned").get_mut(token) {
+        camera.consecutive_failures = consecutive_failures;
+    }
+}
+
+/// Logs when each camera last uploaded, warning about unhealthy cameras.
+pub(crate) fn log_status() {
+    let cameras = CAMERAS.lock().expect("lock poisoned");
+
+    let mut tokens = cameras.keys().collect::<Vec<_>>();
+    tokens.sort_unstable();
+
+    for token in tokens {
+        let camera = &cameras[token];
+
+        match (&camera.last_upload, camera.healthy()) {
+            (Some(last_upload), true) => info!(
+                "camera {} last uploaded {:?} ago",
+                token,
+                last_upload.instant.elapsed()
+            ),
+            (Some(last_upload), false) => warn!(
+                "camera {} last uploaded {:?} ago, at {}",
+                token,
+                last_upload.instant.elapsed(),
+                last_upload.timestamp
+            ),
+            (None, true) => info!("camera {} has not uploaded yet", token),
+            (None, false) => warn!("camera {} has not uploaded yet", token),
+        }
+    }
+}
+
+/// Serves `/healthz`, which is always ok while the process is running, `/readyz`, which is ok
+/// while every camera that's due to has uploaded within the last few intervals, `/status`, which lists when
+/// each camera last uploaded, and `/cameras`, which lists every configured camera's state.
+pub(crate) async fn handle(request: Request<Incoming>) -> Response<Body> {
+    if request.method() != Method::GET {
+        return server::status(StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    match request.uri().path() {
+        "/healthz" => server::full(StatusCode::OK, "text/plain", "ok"),
+        "/readyz" => readiness(),
+        "/status" => status(),
+        "/cameras" => cameras(),
+        _ => server::status(StatusCode::NOT_FOUND),
+    }
+}
+
+fn readiness() -> Response<Body> {
+    let cameras = CAMERAS.lock().expect("lock poisoned");
+
+    let mut unhealthy = cameras
+        .iter()
+        .filter(|(_, camera)| !camera.healthy())
+        .map(|(token, _)| token.as_str())
+        .collect::<Vec<_>>();
+    unhealthy.sort_unstable();
+
+    let status = if unhealthy.is_empty() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    json(status, &Readiness { unhealthy })
+}
+
+fn status() -> Response<Body> {
+    let cameras = CAMERAS.lock().expect("lock poisoned");
+
+    let mut statuses = cameras
+        .iter()
+        .map(|(token, camera)| Status {
+            token,
+            healthy: camera.healthy(),
+            last_upload: camera
+                .last_upload
+                .as_ref()
+                .map(|last_upload| last_upload.timestamp.to_string()),
+            seconds_since_upload: camera
+                .last_upload
+                .as_ref()
+                .map(|last_upload| last_upload.instant.elapsed().as_secs_f64()),
+        })
+        .collect::<Vec<_>>();
+    statuses.sort_unstable_by_key(|status| status.token);
+
+    json(StatusCode::OK, &statuses)
+}
+
+fn cameras() -> Response<Body> {
+    let configured = CONFIGURED.lock().expect("lock poisoned");
+    let cameras = CAMERAS.lock().expect("lock poisoned");
+
+    json(StatusCode::OK, &camera_statuses(&configured, &cameras))
+}
+
+/// What `/cameras` serves, for the control socket's `status` command.
+pub(crate) fn cameras_json() -> anyhow::Result<String> {
+    let configured = CONFIGURED.lock().expect("lock poisoned");
+    let cameras = CAMERAS.lock().expect("lock poisoned");
+
+    Ok(serde_json::to_string(&camera_statuses(
+        &configured,
+        &cameras,
+    ))?)
+}
+
+fn camera_statuses<'a>(
+    configured: &'a [ConfiguredCamera],
+    cameras: &'a HashMap<String, CameraHealth>,
+) -> Vec<CameraStatus<'a>> {
+    configured
+        .iter()
+        .map(|configured| {
+            let camera = cameras.get(&configured.token);
+            let format = camera.and_then(|camera| camera.format.as_ref());
+            CameraStatus {
+                token: &configured.token,
+                url: &configured.url,
+                enabled: configured.enabled,
+                last_success: camera
+                    .and_then(|camera| camera.last_upload.as_ref())
+                    .map(|last_upload| last_upload.timestamp.to_string()),
+                consecutive_failures: camera.map_or(0, |camera| camera.consecutive_failures),
+                last_error: camera.and_then(|camera| camera.last_error.as_deref()),
+                last_jpeg_bytes: camera.and_then(|camera| camera.last_jpeg_bytes),
+                codec: format.map(|(codec, _, _)| codec.as_str()),
+                width: format.map(|(_, width, _)| *width),
+                height: format.map(|(_, _, height)| *height),
+            }
+        })
+        .collect()
+}
+
+fn json(status: StatusCode, body: &impl Serialize) -> Response<Body> {
+    match serde_json::to_string(body) {
+        Ok(body) => server::full(status, "application/json", body),
+        Err(e) => {
+            error!(err=?e, "error encoding health: {:?}", e);
+            server::status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn camera(interval: Duration) -> CameraHealth {
+        CameraHealth {
+            interval,
+            expected_since: Instant::now(),
+            last_upload: None,
+            consecutive_failures: 0,
+            last_error: None,
+            last_jpeg_bytes: None,
+            format: None,
+        }
+    }
+
+    #[test]
+    fn camera_is_healthy_until_its_first_upload_is_due() {
+        let camera = camera(Duration::from_millis(5));
+        assert!(camera.healthy());
+
+        std::thread::sleep(Duration::from_millis(5) * (MAX_MISSED_INTERVALS + 1));
+        assert!(!camera.healthy());
+    }
+
+    #[test]
+    fn paused_camera_is_healthy_without_uploading() {
+        let camera = camera(Duration::MAX);
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(camera.healthy());
+    }
+}
@@ -0,0 +1,701 @@
+//! Transforms applied to decoded frames before they're jpeg encoded.
+
+use anyhow::{bail, Result};
+use jiff::fmt::strtime;
+use jiff::Zoned;
+use openh264::formats::YUVSource;
+use serde::{Deserialize, Deserializer};
+
+use crate::{font, CameraConfig};
+
+/// A decoded frame, which may use any of the common chroma subsamplings.
+pub(crate) trait YuvFrame: YUVSource {
+    /// Size of the u and v planes, defaults to 4:2:0.
+    fn dimensions_uv(&self) -> (usize, usize) {
+        let (width, height) = self.dimensions();
+        (width.div_ceil(2), height.div_ceil(2))
+    }
+}
+
+// openh264 only decodes 4:2:0, and its own `dimensions_uv` rounds odd sizes down.
+impl YuvFrame for openh264::decoder::DecodedYUV<'_> {}
+
+/// A picture returned by a [`FrameDecoder`](crate::stream::FrameDecoder), whichever backend
+/// decoded it. openh264's borrow the decoder until it decodes the next one.
+pub(crate) struct DecodedFrame<'a>(Box<dyn YuvFrame + 'a>);
+
+impl<'a> DecodedFrame<'a> {
+    pub(crate) fn new(frame: impl YuvFrame + 'a) -> Self {
+        Self(Box::new(frame))
+    }
+}
+
+impl YuvFrame for DecodedFrame<'_> {
+    fn dimensions_uv(&self) -> (usize, usize) {
+        self.0.dimensions_uv()
+    }
+}
+
+impl YUVSource for DecodedFrame<'_> {
+    fn dimensions(&self) -> (usize, usize) {
+        self.0.dimensions()
+    }
+
+    fn strides(&self) -> (usize, usize, usize) {
+        self.0.strides()
+    }
+
+    fn y(&self) -> &[u8] {
+        self.0.y()
+    }
+
+    fn u(&self) -> &[u8] {
+        self.0.u()
+    }
+
+    fn v(&self) -> &[u8] {
+        self.0.v()
+    }
+}
+
+/// The image format frames are encoded as.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum OutputFormat {
+    #[default]
+    Jpeg,
+    Png,
+}
+
+impl OutputFormat {
+    /// Guesses the format of an encoded image from its signature, defaulting to jpeg.
+    ///
+    /// Frames from jpeg streams and snapshot urls are passed through as-is, so the format
+    /// can't be assumed from the camera's config.
+    pub(crate) fn detect(image: &[u8]) -> Self {
+        if image.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Self::Png
+        } else {
+            Self::Jpeg
+        }
+    }
+
+    pub(crate) fn content_type(self) -> &'static str {
+        match self {
+            Self::Jpeg => "image/jpeg",
+            Self::Png => "image/png",
+        }
+    }
+
+    pub(crate) fn extension(self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::Png => "png",
+        }
+    }
+}
+
+/// Clockwise rotation applied to a frame.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum Rotation {
+    #[default]
+    None,
+    Cw90,
+    Cw180,
+    Cw270,
+}
+
+impl<'de> Deserialize<'de> for Rotation {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match u16::deserialize(deserializer)? {
+            0 => Ok(Self::None),
+            90 => Ok(Self::Cw90),
+            180 => Ok(Self::Cw180),
+            270 => Ok(Self::Cw270),
+            degrees => Err(serde::de::Error::custom(format!(
+                "rotate must be 0, 90, 180 or 270, got {}",
+                degrees
+            ))),
+        }
+    }
+}
+
+/// A region of the camera's frame, in pixels.
+///
+/// Offsets and sizes are rounded down to even numbers to line up with the chroma planes.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+pub(crate) struct Crop {
+    pub(crate) x: u32,
+    pub(crate) y: u32,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+}
+
+/// Draws the time each frame was captured onto it.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub(crate) struct TimestampOverlay {
+    #[serde(default = "default_overlay_enabled")]
+    pub(crate) enabled: bool,
+
+    /// Corner of the frame to draw the timestamp in.
+    #[serde(default)]
+    pub(crate) position: Position,
+
+    /// strftime style format, in local time.
+    #[serde(default = "default_overlay_format")]
+    pub(crate) format: String,
+}
+
+fn default_overlay_enabled() -> bool {
+    true
+}
+
+fn default_overlay_format() -> String {
+    "%Y-%m-%d %H:%M:%S".to_string()
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Position {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    #[default]
+    BottomRight,
+}
+
+impl TimestampOverlay {
+    /// Formats the current local time.
+    pub(crate) fn text(&self) -> Result<String> {
+        Ok(strtime::format(&self.format, &Zoned::now())?)
+    }
+}
+
+/// The transforms configured for a camera.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Transform {
+    /// Applied before everything else, in the camera's orientation.
+    crop: Option<Crop>,
+    rotate: Rotation,
+    /// Flips are applied after rotating.
+    flip_h: bool,
+    flip_v: bool,
+    /// Larger images are downscaled to fit, after rotating.
+    max_width: Option<usize>,
+    max_height: Option<usize>,
+    /// Maps each luma value to its brightness, contrast and gamma adjusted value, `None` if
+    /// they're all neutral.
+    levels: Option<[u8; 256]>,
+    /// Drawn last, so the text isn't rotated or scaled.
+    overlay: Option<TimestampOverlay>,
+    label: Option<(String, Position)>,
+}
+
+impl Transform {
+    pub(crate) fn new(config: &CameraConfig) -> Self {
+        Self {
+            crop: config.crop,
+            rotate: config.rotate.unwrap_or_default(),
+            flip_h: config.flip_h,
+            flip_v: config.flip_v,
+            max_width: config.max_width.map(|width| width as usize),
+            max_height: config.max_height.map(|height| height as usize),
+            levels: levels(
+                config.brightness.unwrap_or(0.0),
+                config.contrast.unwrap_or(1.0),
+                config.gamma.unwrap_or(1.0),
+            ),
+            overlay: config
+                .timestamp_overlay
+                .clone()
+                .filter(|overlay| overlay.enabled),
+            label: config.label.clone().map(|label| {
+                let position = config.label_position.unwrap_or(Position::TopLeft);
+                (label, position)
+            }),
+        }
+    }
+
+    /// Whether applying the transform would leave frames unchanged.
+    pub(crate) fn is_identity(&self) -> bool {
+        self.crop.is_none()
+            && self.rotate == Rotation::None
+            && !self.flip_h
+            && !self.flip_v
+            && self.max_width.is_none()
+            && self.max_height.is_none()
+            && self.levels.is_none()
+            && self.overlay.is_none()
+            && self.label.is_none()
+    }
+
+    pub(crate) fn apply(&self, mut image: Image) -> Result<Image> {
+        if let Some(crop) = self.crop {
+            image = image.crop(crop)?;
+        }
+
+        image = image.rotate(self.rotate);
+
+        if self.flip_h {
+            image.flip_h();
+        }
+
+        if self.flip_v {
+            image.flip_v();
+        }
+
+        let (width, height) = (image.width, image.height);
+        let max_width = self.max_width.unwrap_or(width);
+        let max_height = self.max_height.unwrap_or(height);
+
+        if width > max_width || height > max_height {
+            // scale by whichever side is furthest over its limit, keeping the aspect ratio.
+            let (width, height) = if width * max_height > height * max_width {
+                (max_width, height * max_width / width)
+            } else {
+                (width * max_height / height, max_height)
+            };
+
+            image = image.resize((width & !1).max(2), (height & !1).max(2));
+        }
+
+        if let Some(levels) = &self.levels {
+            image.adjust_levels(levels);
+        }
+
+        let mut lines = Vec::new();
+
+        if let Some((label, position)) = &self.label {
+            lines.push((label.clone(), *position));
+        }
+
+        if let Some(overlay) = &self.overlay {
+            lines.push((overlay.text()?, overlay.position));
+        }
+
+        // text sharing a corner is stacked, starting from the edge of the frame.
+        for (i, (text, position)) in lines.iter().enumerate() {
+            let line = lines[..i].iter().filter(|(_, p)| p == position).count();
+            image.draw_text(text, *position, line);
+        }
+
+        Ok(image)
+    }
+}
+
+/// A lookup table applying `gamma`, then `contrast` around mid grey, then adding `brightness`,
+/// `None` if they're neutral (0, 1 and 1).
+fn levels(brightness: f64, contrast: f64, gamma: f64) -> Option<[u8; 256]> {
+    if brightness == 0.0 && contrast == 1.0 && gamma == 1.0 {
+        return None;
+    }
+
+    let mut levels = [0; 256];
+    for (value, level) in levels.iter_mut().enumerate() {
+        let x = (value as f64 / 255.0).powf(1.0 / gamma);
+        let x = (x - 0.5) * contrast + 0.5 + brightness;
+        *level = (x * 255.0).round().clamp(0.0, 255.0) as u8;
+    }
+
+    Some(levels)
+}
+
+/// An owned 4:2:0 picture with tightly packed planes and even dimensions.
+pub(crate) struct Image {
+    width: usize,
+    height: usize,
+    y: Vec<u8>,
+    u: Vec<u8>,
+    v: Vec<u8>,
+}
+
+impl Image {
+    /// Copies a decoded frame, dropping the last row or column if its dimensions are odd.
+    ///
+    /// 4:2:2 and 4:4:4 chroma is subsampled to 4:2:0 by skipping columns and rows.
+    pub(crate) fn from_yuv(frame: &impl YuvFrame) -> Self {
+        let (width, height) = frame.dimensions();
+        let (uv_width, uv_height) = frame.dimensions_uv();
+        let step = (
+            if uv_width < width { 1 } else { 2 },
+            if uv_height < height { 1 } else { 2 },
+        );
+
+        let (width, height) = (width & !1, height & !1);
+        let (stride_y, stride_u, stride_v) = frame.strides();
+
+        Self {
+            width,
+            height,
+            y: copy_plane(frame.y(), stride_y, width, height),
+            u: sample_plane(frame.u(), stride_u, width / 2, height / 2, step),
+            v: sample_plane(frame.v(), stride_v, width / 2, height / 2, step),
+        }
+    }
+
+    fn crop(self, crop: Crop) -> Result<Self> {
+        let (x, y) = (crop.x as usize & !1, crop.y as usize & !1);
+        let (width, height) = (crop.width as usize & !1, crop.height as usize & !1);
+
+        if width == 0 || height == 0 || x + width > self.width || y + height > self.height {
+            bail!(
+                "crop {}x{} at ({}, {}) doesn't fit in the {}x{} frame",
+                width,
+                height,
+                x,
+                y,
+                self.width,
+                self.height
+            );
+        }
+
+        let plane = |plane: &[u8], stride: usize, scale: usize| {
+            let (x, y, width, height) = (x / scale, y / scale, width / scale, height / scale);
+            copy_plane(&plane[y * stride + x..], stride, width, height)
+        };
+
+        Ok(Self {
+            width,
+            height,
+            y: plane(&self.y, self.width, 1),
+            u: plane(&self.u, self.width / 2, 2),
+            v: plane(&self.v, self.width / 2, 2),
+        })
+    }
+
+    fn rotate(self, rotation: Rotation) -> Self {
+        let (width, height) = match rotation {
+            Rotation::None | Rotation::Cw180 => (self.width, self.height),
+            Rotation::Cw90 | Rotation::Cw270 => (self.height, self.width),
+        };
+
+        Self {
+            width,
+            height,
+            y: rotate_plane(self.y, self.width, self.height, rotation),
+            u: rotate_plane(self.u, self.width / 2, self.height / 2, rotation),
+            v: rotate_plane(self.v, self.width / 2, self.height / 2, rotation),
+        }
+    }
+
+    /// Downscales the image by averaging the source pixels covered by each output pixel.
+    fn resize(self, width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            y: resize_plane(&self.y, self.width, self.height, width, height),
+            u: resize_plane(
+                &self.u,
+                self.width / 2,
+                self.height / 2,
+                width / 2,
+                height / 2,
+            ),
+            v: resize_plane(
+                &self.v,
+                self.width / 2,
+                self.height / 2,
+                width / 2,
+                height / 2,
+            ),
+        }
+    }
+
+    /// Maps each luma value through `levels`, the chroma is left as is.
+    fn adjust_levels(&mut self, levels: &[u8; 256]) {
+        for value in &mut self.y {
+            *value = levels[*value as usize];
+        }
+    }
+
+    /// Mirrors the image left to right.
+    fn flip_h(&mut self) {
+        for (plane, width) in self.planes_mut() {
+            for row in plane.chunks_exact_mut(width) {
+                row.reverse();
+            }
+        }
+    }
+
+    /// Mirrors the image top to bottom.
+    fn flip_v(&mut self) {
+        for (plane, width) in self.planes_mut() {
+            let height = plane.len() / width;
+            for y in 0..height / 2 {
+                let (top, bottom) = plane.split_at_mut((height - 1 - y) * width);
+                top[y * width..(y + 1) * width].swap_with_slice(&mut bottom[..width]);
+            }
+        }
+    }
+
+    /// Draws white text on a translucent black box in the given corner, scaled with the image.
+    ///
+    /// `line` moves the box away from the edge by that many boxes, so text can be stacked.
+    fn draw_text(&mut self, text: &str, position: Position, line: usize) {
+        let chars = text.chars().count();
+        if chars == 0 {
+            return;
+        }
+
+        // eg: 4x at 1080p, so the text stays legible in prusa connect's thumbnails.
+        let scale = (self.height / 270).max(1);
+        let padding = 2 * scale;
+        let advance = (font::WIDTH + 1) * scale;
+
+        // keep the box on even pixels, so it lines up with the chroma planes.
+        let box_width = (chars * advance - scale + 2 * padding + 1) & !1;
+        let box_height = (font::HEIGHT * scale + 2 * padding + 1) & !1;
+
+        let x0 = match position {
+            Position::TopLeft | Position::BottomLeft => padding,
+            Position::TopRight | Position::BottomRight => {
+                self.width.saturating_sub(box_width + padding)
+            }
+        } & !1;
+        let offset = line * (box_height + padding);
+        let y0 = match position {
+            Position::TopLeft | Position::TopRight => padding + offset,
+            Position::BottomLeft | Position::BottomRight => {
+                self.height.saturating_sub(box_height + padding + offset)
+            }
+        } & !1;
+
+        // eg: a crop narrower than the padding.
+        if x0 >= self.width || y0 >= self.height {
+            return;
+        }
+
+        let x1 = (x0 + box_width).min(self.width);
+        let y1 = (y0 + box_height).min(self.height);
+
+        // darken and desaturate the box by half.
+        let width = self.width;
+        for y in y0..y1 {
+            for pixel in &mut self.y[y * width + x0..y * width + x1] {
+                *pixel /= 2;
+            }
+        }
+
+        let chroma_width = width / 2;
+        for plane in [&mut self.u, &mut self.v] {
+            for y in y0 / 2..y1 / 2 {
+                for pixel in &mut plane[y * chroma_width + x0 / 2..y * chroma_width + x1 / 2] {
+                    *pixel = ((*pixel as u16 + 128) / 2) as u8;
+                }
+            }
+        }
+
+        for (i, c) in text.chars().enumerate() {
+            let left = x0 + padding + i * advance;
+            let top = y0 + padding;
+
+            for (row, bits) in font::glyph(c).into_iter().enumerate() {
+                for column in 0..font::WIDTH {
+                    if bits & (1 << (font::WIDTH - 1 - column)) == 0 {
+                        continue;
+                    }
+
+                    for dy in 0..scale {
+                        for dx in 0..scale {
+                            let (x, y) = (left + column * scale + dx, top + row * scale + dy);
+                            if x < x1 && y < y1 {
+                                self.y[y * width + x] = 235;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Each plane along with its width.
+    fn planes_mut(&mut self) -> [(&mut [u8], usize); 3] {
+        let (width, chroma_width) = (self.width, self.width / 2);
+        [
+            (&mut self.y, width),
+            (&mut self.u, chroma_width),
+            (&mut self.v, chroma_width),
+        ]
+    }
+}
+
+impl YuvFrame for Image {}
+
+impl YUVSource for Image {
+    fn dimensions(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    fn strides(&self) -> (usize, usize, usize) {
+        (self.width, self.width / 2, self.width / 2)
+    }
+
+    fn y(&self) -> &[u8] {
+        &self.y
+    }
+
+    fn u(&self) -> &[u8] {
+        &self.u
+    }
+
+    fn v(&self) -> &[u8] {
+        &self.v
+    }
+}
+
+fn copy_plane(data: &[u8], stride: usize, width: usize, height: usize) -> Vec<u8> {
+    let mut plane = Vec::with_capacity(width * height);
+
+    for row in data.chunks(stride).take(height) {
+        plane.extend_from_slice(&row[..width]);
+    }
+
+    plane
+}
+
+/// Copies every `step`th column and row of a plane.
+fn sample_plane(
+    data: &[u8],
+    stride: usize,
+    width: usize,
+    height: usize,
+    (step_x, step_y): (usize, usize),
+) -> Vec<u8> {
+    if (step_x, step_y) == (1, 1) {
+        return copy_plane(data, stride, width, height);
+    }
+
+    let mut plane = Vec::with_capacity(width * height);
+
+    for row in data.chunks(stride).step_by(step_y).take(height) {
+        plane.extend(row.iter().step_by(step_x).take(width));
+    }
+
+    plane
+}
+
+fn rotate_plane(mut plane: Vec<u8>, width: usize, height: usize, rotation: Rotation) -> Vec<u8> {
+    match rotation {
+        Rotation::None => plane,
+        // rotating by 180 degrees reverses the order of every pixel.
+        Rotation::Cw180 => {
+            plane.reverse();
+            plane
+        }
+        Rotation::Cw90 => {
+            // the rotated plane is `height` wide, its first column is the source's last row.
+            let mut rotated = Vec::with_capacity(plane.len());
+            for x in 0..width {
+                for y in (0..height).rev() {
+                    rotated.push(plane[y * width + x]);
+                }
+            }
+            rotated
+        }
+        Rotation::Cw270 => {
+            // the rotated plane is `height` wide, its first row is the source's last column.
+            let mut rotated = Vec::with_capacity(plane.len());
+            for x in (0..width).rev() {
+                for y in 0..height {
+                    rotated.push(plane[y * width + x]);
+                }
+            }
+            rotated
+        }
+    }
+}
+
+/// Box filters a plane down to `width` x `height`, which must not be larger than the source.
+fn resize_plane(
+    plane: &[u8],
+    src_width: usize,
+    src_height: usize,
+    width: usize,
+    height: usize,
+) -> Vec<u8> {
+    // the range of source pixels covered by output pixel `i`, always at least one pixel.
+    let span = |i: usize, src: usize, dst: usize| {
+        let start = i * src / dst;
+        let end = ((i + 1) * src / dst).max(start + 1);
+        start..end
+    };
+
+    let mut resized = Vec::with_capacity(width * height);
+
+    for y in 0..height {
+        let rows = span(y, src_height, height);
+
+        for x in 0..width {
+            let columns = span(x, src_width, width);
+
+            let mut sum = 0u32;
+            for row in rows.clone() {
+                let row = &plane[row * src_width..][columns.clone()];
+                sum += row.iter().map(|&p| p as u32).sum::<u32>();
+            }
+
+            let count = (rows.len() * columns.len()) as u32;
+            resized.push(((sum + count / 2) / count) as u8);
+        }
+    }
+
+    resized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 4x4 image whose samples are numbered in raster order, chroma from 100 and 200.
+    fn numbered() -> Image {
+        Image {
+            width: 4,
+            height: 4,
+            y: (0..16).collect(),
+            u: (100..104).collect(),
+            v: (200..204).collect(),
+        }
+    }
+
+    #[test]
+    fn flip_h_mirrors_each_row() {
+        let mut image = numbered();
+        image.flip_h();
+
+        assert_eq!(
+            image.y,
+            [3, 2, 1, 0, 7, 6, 5, 4, 11, 10, 9, 8, 15, 14, 13, 12]
+        );
+        assert_eq!(image.u, [101, 100, 103, 102]);
+        assert_eq!(image.v, [201, 200, 203, 202]);
+    }
+
+    #[test]
+    fn flip_v_swaps_rows() {
+        let mut image = numbered();
+        image.flip_v();
+
+        assert_eq!(
+            image.y,
+            [12, 13, 14, 15, 8, 9, 10, 11, 4, 5, 6, 7, 0, 1, 2, 3]
+        );
+        assert_eq!(image.u, [102, 103, 100, 101]);
+        assert_eq!(image.v, [202, 203, 200, 201]);
+    }
+
+    #[test]
+    fn draw_text_skips_images_narrower_than_the_padding() {
+        let mut image = Image {
+            width: 4,
+            height: 1080,
+            y: vec![0; 4 * 1080],
+            u: vec![128; 2 * 540],
+            v: vec![128; 2 * 540],
+        };
+
+        image.draw_text("label", Position::TopLeft, 0);
+        image.draw_text("label", Position::BottomLeft, 0);
+
+        assert!(image.y.iter().all(|&y| y == 0));
+    }
+}
@@ -0,0 +1,205 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use hyper::body::Incoming;
+use hyper::{Method, Request, Response, StatusCode};
+use prometheus::{
+    exponential_buckets, register_gauge, register_gauge_vec, register_histogram_vec,
+    register_int_counter_vec, Encoder, Gauge, GaugeVec, HistogramVec, IntCounterVec, TextEncoder,
+};
+use tracing::error;
+
+use crate::server::{self, Body};
+
+pub(crate) static FRAMES_CAPTURED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "prusa_rtsp_streamer_frames_captured_total",
+        "Frames captured from the camera.",
+        &["token"]
+    )
+    .expect("valid metric")
+});
+
+pub(crate) static FRAMES_SKIPPED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "prusa_rtsp_streamer_frames_skipped_total",
+        "Frames not published because they barely changed.",
+        &["token"]
+    )
+    .expect("valid metric")
+});
+
+pub(crate) static UPLOADS_SUCCEEDED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "prusa_rtsp_streamer_uploads_succeeded_total",
+        "Frames successfully uploaded.",
+        &["token"]
+    )
+    .expect("valid metric")
+});
+
+pub(crate) static UPLOADS_FAILED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "prusa_rtsp_streamer_uploads_failed_total",
+        "Frames that failed to upload after all retries.",
+        &["token"]
+    )
+    .expect("valid metric")
+});
+
+pub(crate) static TARGET_UPLOADS_SUCCEEDED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "prusa_rtsp_streamer_target_uploads_succeeded_total",
+        "Frames successfully uploaded, per upload_url and upload_targets url.",
+        &["token", "target"]
+    )
+    .expect("valid metric")
+});
+
+pub(crate) static TARGET_UPLOADS_FAILED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "prusa_rtsp_streamer_target_uploads_failed_total",
+        "Frames that failed to upload after all retries, per upload_url and upload_targets url.",
+        &["token", "target"]
+    )
+    .expect("valid metric")
+});
+
+pub(crate) static DECODE_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "prusa_rtsp_streamer_decode_errors_total",
+        "Frames that failed to decode or encode.",
+        &["token"]
+    )
+    .expect("valid metric")
+});
+
+pub(crate) static TIMEOUTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "prusa_rtsp_streamer_timeouts_total",
+        "Timeouts connecting to the camera or waiting for a frame from it.",
+        &["token"]
+    )
+    .expect("valid metric")
+});
+
+pub(crate) static DECODE_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "prusa_rtsp_streamer_decode_duration_seconds",
+        "Time spent decoding a keyframe and encoding it.",
+        &["token"]
+    )
+    .expect("valid metric")
+});
+
+pub(crate) static JPEG_BYTES: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "prusa_rtsp_streamer_jpeg_bytes",
+        "Size of captured jpegs.",
+        &["token"],
+        exponential_buckets(16384.0, 2.0, 10).expect("valid buckets")
+    )
+    .expect("valid metric")
+});
+
+static SECONDS_SINCE_UPLOAD: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        "prusa_rtsp_streamer_seconds_since_last_upload",
+        "Seconds since the last successful upload, or since startup if there hasn't been one.",
+        &["token"]
+    )
+    .expect("valid metric")
+});
+
+static LAST_UPLOAD_TIMESTAMP: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        "prusa_rtsp_streamer_last_upload_timestamp_seconds",
+        "Unix time of the last successful upload.",
+        &["token"]
+    )
+    .expect("valid metric")
+});
+
+/// `UPLOAD_RATE` is averaged over this long.
+const UPLOAD_RATE_WINDOW: Duration = Duration::from_secs(10);
+
+static UPLOAD_RATE: LazyLock<Gauge> = LazyLock::new(|| {
+    register_gauge!(
+        "prusa_rtsp_streamer_upload_bytes_per_second",
+        "Bytes uploaded per second across all cameras, averaged over the last 10 seconds."
+    )
+    .expect("valid metric")
+});
+
+/// When and how many bytes were recently sent by uploads, used to compute `UPLOAD_RATE`.
+static UPLOADED: LazyLock<Mutex<VecDeque<(Instant, usize)>>> = LazyLock::new(Default::default);
+
+/// When each camera last uploaded successfully, used to compute `SECONDS_SINCE_UPLOAD`.
+static LAST_UPLOAD: LazyLock<Mutex<HashMap<String, Instant>>> = LazyLock::new(Default::default);
+
+/// Starts tracking the time since the camera's last upload.
+pub(crate) fn register_camera(token: &str) {
+    LAST_UPLOAD
+        .lock()
+        .expect("lock poisoned")
+        .insert(token.to_string(), Instant::now());
+}
+
+pub(crate) fn record_upload(token: &str) {
+    UPLOADS_SUCCEEDED.with_label_values(&[token]).inc();
+    LAST_UPLOAD_TIMESTAMP
+        .with_label_values(&[token])
+        .set(jiff::Timestamp::now().as_millisecond() as f64 / 1000.0);
+    register_camera(token);
+}
+
+/// Records bytes sent by an upload, to upload_url or s3.
+pub(crate) fn record_upload_bytes(bytes: usize) {
+    let mut uploaded = UPLOADED.lock().expect("lock poisoned");
+    uploaded.push_back((Instant::now(), bytes));
+    prune_uploaded(&mut uploaded);
+}
+
+fn prune_uploaded(uploaded: &mut VecDeque<(Instant, usize)>) {
+    while uploaded
+        .front()
+        .is_some_and(|(instant, _)| instant.elapsed() > UPLOAD_RATE_WINDOW)
+    {
+        uploaded.pop_front();
+    }
+}
+
+/// Serves metrics in the prometheus text format at `/metrics`.
+pub(crate) async fn handle(request: Request<Incoming>) -> Response<Body> {
+    if request.method() != Method::GET {
+        return server::status(StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    if request.uri().path() != "/metrics" {
+        return server::status(StatusCode::NOT_FOUND);
+    }
+
+    for (token, instant) in LAST_UPLOAD.lock().expect("lock poisoned").iter() {
+        SECONDS_SINCE_UPLOAD
+            .with_label_values(&[token])
+            .set(instant.elapsed().as_secs_f64());
+    }
+
+    {
+        let mut uploaded = UPLOADED.lock().expect("lock poisoned");
+        prune_uploaded(&mut uploaded);
+        let bytes = uploaded.iter().map(|(_, bytes)| bytes).sum::<usize>();
+        UPLOAD_RATE.set(bytes as f64 / UPLOAD_RATE_WINDOW.as_secs_f64());
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        error!(err=?e, "error encoding metrics: {:?}", e);
+        return server::status(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    server::full(StatusCode::OK, encoder.format_type(), buffer)
+}
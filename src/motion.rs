@@ -0,0 +1,175 @@
+use std::hash::{DefaultHasher, Hasher};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use tracing::trace;
+use turbojpeg::{Decompressor, PixelFormat, ScalingFactor};
+
+use crate::image::OutputFormat;
+
+pub(crate) const DEFAULT_MOTION_KEEPALIVE_SEC: u64 = 300;
+
+/// Frames are compared at this fraction of their size, which is plenty to notice a print moving.
+const SCALE: ScalingFactor = ScalingFactor::ONE_EIGHTH;
+
+/// Skips frames that are identical to, or with a threshold barely differ from, the last frame that
+/// was published.
+pub(crate) struct MotionDetector {
+    /// Mean absolute luma difference, from 0 to 255, below which a frame is skipped. Only
+    /// byte-identical frames are skipped if unset.
+    threshold: Option<f64>,
+    /// A frame is published at least this often, however little it changed.
+    keepalive: Duration,
+    last: Option<Reference>,
+    /// The frame `changed` last accepted, which replaces `last` once it has been published.
+    pending: Option<Reference>,
+}
+
+/// The last published frame.
+struct Reference {
+    hash: u64,
+    /// The downscaled luma, `None` without a threshold or if the frame couldn't be decoded.
+    luma: Option<Luma>,
+    published: Instant,
+}
+
+struct Luma {
+    pixels: Vec<u8>,
+    width: usize,
+    height: usize,
+}
+
+impl MotionDetector {
+    pub(crate) fn new(threshold: Option<f64>, keepalive: Duration) -> Self {
+        Self {
+            threshold,
+            keepalive,
+            last: None,
+            pending: None,
+        }
+    }
+
+    pub(crate) fn keepalive(&self) -> Duration {
+        self.keepalive
+    }
+
+    /// Whether `frame` changed enough to publish, in which case `commit` makes it the frame later
+    /// ones are compared against once it has been.
+    ///
+    /// Frames that can't be decoded, eg: pngs, are only compared byte for byte.
+    pub(crate) fn changed(&mut self, frame: &[u8]) -> bool {
+        self.pending = None;
+
+        let mut hasher = DefaultHasher::new();
+        hasher.write(frame);
+        let hash = hasher.finish();
+
+        let luma = self.threshold.and_then(|_| decode_luma(frame));
+
+        if let Some(last) = &self.last {
+            if last.published.elapsed() < self.keepalive {
+                if last.hash == hash {
+                    trace!("frame is identical to the last published frame");
+                    return false;
+                }
+
+                if let (Some(threshold), Some(last), Some(luma)) =
+                    (self.threshold, &last.luma, &luma)
+                {
+                    if (last.width, last.height) == (luma.width, luma.height) {
+                        let difference = mean_difference(&last.pixels, &luma.pixels);
+                        trace!("mean luma difference {:.2}", difference);
+
+                        if difference < threshold {
+                            return false;
+                        }
+                    }
+                }
+            }
+        }
+
+        self.pending = Some(Reference {
+            hash,
+            luma,
+            published: Instant::now(),
+        });
+
+        true
+    }
+
+    /// Compares later frames against the one `changed` last accepted, now that it was published.
+    pub(crate) fn commit(&mut self) {
+        if let Some(mut reference) = self.pending.take() {
+            reference.published = Instant::now();
+            self.last = Some(reference);
+        }
+    }
+}
+
+/// Decodes a jpeg's luma at `SCALE`, `None` if it isn't a jpeg or can't be decoded.
+fn decode_luma(frame: &[u8]) -> Option<Luma> {
+    if OutputFormat::detect(frame) != OutputFormat::Jpeg {
+        return None;
+    }
+
+    match try_decode_luma(frame) {
+        Ok(luma) => Some(luma),
+        Err(e) => {
+            trace!(err=?e, "error decoding frame for motion detection: {:?}", e);
+            None
+        }
+    }
+}
+
+fn try_decode_luma(jpeg: &[u8]) -> Result<Luma> {
+    let mut decompressor = Decompressor::new()?;
+    decompressor.set_scaling_factor(SCALE)?;
+
+    let header = decompressor.read_header(jpeg)?.scaled(SCALE);
+    let mut image = turbojpeg::Image {
+        pixels: vec![0; header.width * header.height],
+        width: header.width,
+        pitch: header.width,
+        height: header.height,
+        format: PixelFormat::GRAY,
+    };
+    decompressor.decompress(jpeg, image.as_deref_mut())?;
+
+    Ok(Luma {
+        pixels: image.pixels,
+        width: header.width,
+        height: header.height,
+    })
+}
+
+fn mean_difference(a: &[u8], b: &[u8]) -> f64 {
+    if a.is_empty() {
+        return 0.0;
+    }
+
+    let total: u64 = a
+        .iter()
+        .zip(b)
+        .map(|(a, b)| u64::from(a.abs_diff(*b)))
+        .sum();
+
+    total as f64 / a.len() as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unpublished_frame_isnt_compared_against() {
+        let mut motion = MotionDetector::new(None, Duration::from_secs(300));
+        let frame = b"not a jpeg";
+
+        assert!(motion.changed(frame));
+        // eg: uploading it failed.
+        assert!(motion.changed(frame));
+
+        motion.commit();
+        assert!(!motion.changed(frame));
+    }
+}
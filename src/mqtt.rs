@@ -0,0 +1,115 @@
+//! Publishes snapshots to an MQTT broker, eg: for Home Assistant.
+
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use bytes::Bytes;
+use rumqttc::{AsyncClient, MqttOptions, QoS};
+use serde::Deserialize;
+use tracing::{debug, warn};
+use url::Url;
+
+use crate::{Secret, SecretUrl};
+
+const DEFAULT_PORT: u16 = 1883;
+const KEEP_ALIVE_SEC: u64 = 30;
+const RECONNECT_DELAY_SEC: u64 = 5;
+/// Publishes queued while disconnected, beyond this frames are dropped.
+const QUEUE_CAPACITY: usize = 16;
+/// rumqttc defaults to 10KiB, far smaller than a snapshot.
+const MAX_PACKET_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub(crate) struct MqttConfig {
+    /// Eg: "mqtt://192.168.0.10:1883".
+    pub(crate) url: SecretUrl,
+    #[serde(default = "default_topic")]
+    pub(crate) topic: String,
+    #[serde(default = "default_client_id")]
+    pub(crate) client_id: String,
+    pub(crate) username: Option<String>,
+    pub(crate) password: Option<Secret>,
+}
+
+fn default_topic() -> String {
+    "snapshots/{token}".to_string()
+}
+
+fn default_client_id() -> String {
+    "prusa-rtsp-streamer".to_string()
+}
+
+impl MqttConfig {
+    pub(crate) fn validate(&self) -> Result<()> {
+        self.options().map(drop)
+    }
+
+    fn options(&self) -> Result<MqttOptions> {
+        let url = Url::parse(self.url.expose()).context("invalid mqtt url")?;
+
+        if url.scheme() != "mqtt" {
+            bail!("mqtt url must start with mqtt://, got {}", url.scheme());
+        }
+
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow!("mqtt url has no host"))?;
+
+        let mut options =
+            MqttOptions::new(&self.client_id, host, url.port().unwrap_or(DEFAULT_PORT));
+        options
+            .set_keep_alive(Duration::from_secs(KEEP_ALIVE_SEC))
+            .set_max_packet_size(MAX_PACKET_SIZE, MAX_PACKET_SIZE);
+
+        match (&self.username, &self.password) {
+            (Some(username), Some(password)) => {
+                options.set_credentials(username, password.expose());
+            }
+            (Some(_), None) => bail!("mqtt username set without password"),
+            (None, _) => {}
+        }
+
+        Ok(options)
+    }
+}
+
+/// A connection to the broker shared by all cameras, kept open (and reconnected) in the
+/// background.
+#[derive(Clone)]
+pub(crate) struct Mqtt {
+    client: AsyncClient,
+    topic: String,
+}
+
+impl Mqtt {
+    pub(crate) fn connect(config: &MqttConfig) -> Result<Self> {
+        let (client, mut event_loop) = AsyncClient::new(config.options()?, QUEUE_CAPACITY);
+
+        tokio::spawn(async move {
+            loop {
+                match event_loop.poll().await {
+                    Ok(event) => debug!("mqtt: {:?}", event),
+                    Err(e) => {
+                        warn!(err=?e, "mqtt connection error, reconnecting: {:?}", e);
+                        tokio::time::sleep(Duration::from_secs(RECONNECT_DELAY_SEC)).await;
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            client,
+            topic: config.topic.clone(),
+        })
+    }
+
+    /// Queues a frame to be published as the retained message on the camera's topic, without
+    /// waiting for it to be delivered.
+    pub(crate) fn publish(&self, token: &str, frame: Bytes) -> Result<()> {
+        let topic = self.topic.replace("{token}", token);
+
+        self.client
+            .try_publish(topic, QoS::AtLeastOnce, true, frame)
+            .context("unable to queue mqtt publish")
+    }
+}
@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures_util::StreamExt;
+use http_body_util::{BodyExt, StreamBody};
+use hyper::body::{Frame, Incoming};
+use hyper::header::CONTENT_TYPE;
+use hyper::{Method, Request, Response, StatusCode};
+use tokio::sync::{mpsc, oneshot, watch};
+
+use crate::image::OutputFormat;
+use crate::server::{self, Body};
+
+const BOUNDARY: &str = "frame";
+
+/// The length of a clip if `seconds` isn't given.
+const DEFAULT_CLIP_SEC: u64 = 30;
+const MAX_CLIP_SEC: u64 = 300;
+
+/// The most recent frame captured by a camera, `None` until the first capture.
+pub(crate) type LastFrame = watch::Receiver<Option<Bytes>>;
+
+/// Asks a camera to record a clip of the given length.
+pub(crate) type ClipRequests = mpsc::Sender<Duration>;
+
+/// Receives the size of a requested snapshot once it's been uploaded, or why it failed.
+pub(crate) type SnapshotReply = oneshot::Sender<anyhow::Result<usize>>;
+
+/// Asks a camera to capture and upload a frame now.
+pub(crate) type SnapshotRequests = mpsc::Sender<SnapshotReply>;
+
+#[derive(Clone)]
+struct PreviewCamera {
+    frames: LastFrame,
+    /// `None` if `clip_dir` isn't set.
+    clips: Option<ClipRequests>,
+    snapshots: SnapshotRequests,
+}
+
+/// Serves the latest frame from each camera at `/camera/<token>`, and an MJPEG stream of
+/// frames as they're captured at `/camera/<token>/stream`. A `POST` to
+/// `/camera/<token>/clip?seconds=<seconds>` records a clip, and a `POST` to
+/// `/camera/<token>/snapshot` captures and uploads a frame outside the camera's interval.
+///
+/// Cameras are added and removed as they're started and stopped.
+#[derive(Clone, Default)]
+pub(crate) struct Preview {
+    cameras: Arc<RwLock<HashMap<String, PreviewCamera>>>,
+}
+
+impl Preview {
+    pub(crate) fn insert(
+        &self,
+        token: String,
+        frames: LastFrame,
+        clips: Option<ClipRequests>,
+        snapshots: SnapshotRequests,
+    ) {
+        self.cameras.write().expect("preview lock poisoned").insert(
+            token,
+            PreviewCamera {
+                frames,
+                clips,
+                snapshots,
+            },
+        );
+    }
+
+    pub(crate) fn remove(&self, token: &str) {
+        self.cameras
+            .write()
+            .expect("preview lock poisoned")
+            .remove(token);
+    }
+
+    pub(crate) async fn handle(self, request: Request<Incoming>) -> Response<Body> {
+        let Some(path) = request.uri().path().strip_prefix("/camera/") else {
+            return server::status(StatusCode::NOT_FOUND);
+        };
+
+        let (token, endpoint) = match path.rsplit_once('/') {
+            Some((token, endpoint @ ("stream" | "clip" | "snapshot"))) => (token, endpoint),
+            _ => (path, ""),
+        };
+
+        let method = match endpoint {
+            "clip" | "snapshot" => Method::POST,
+            _ => Method::GET,
+        };
+        if request.method() != method {
+            return server::status(StatusCode::METHOD_NOT_ALLOWED);
+        }
+
+        let camera = self
+            .cameras
+            .read()
+            .expect("preview lock poisoned")
+            .get(token)
+            .cloned();
+
+        let Some(PreviewCamera {
+            frames,
+            clips,
+            snapshots,
+        }) = camera
+        else {
+            return server::status(StatusCode::NOT_FOUND);
+        };
+
+        match endpoint {
+            "stream" => return mjpeg(frames),
+            "clip" => return clip(clips, request.uri().query()),
+            "snapshot" => return snapshot(snapshots).await,
+            _ => {}
+        }
+
+        let frame = frames.borrow().clone();
+        match frame {
+            Some(frame) => {
+                let content_type = OutputFormat::detect(&frame).content_type();
+                server::full(StatusCode::OK, content_type, frame)
+            }
+            None => server::full(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "text/plain",
+                "no frame captured yet",
+            ),
+        }
+    }
+}
+
+/// Asks the camera to record a clip of `seconds` from the query string.
+fn clip(clips: Option<ClipRequests>, query: Option<&str>) -> Response<Body> {
+    let Some(clips) = clips else {
+        return server::full(StatusCode::NOT_FOUND, "text/plain", "clip_dir is not set");
+    };
+
+    let seconds = url::form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+        .find(|(key, _)| key == "seconds")
+        .map(|(_, value)| value.parse::<u64>());
+
+    let seconds = match seconds {
+        None => DEFAULT_CLIP_SEC,
+        Some(Ok(seconds)) if (1..=MAX_CLIP_SEC).contains(&seconds) => seconds,
+        Some(_) => {
+            return server::full(
+                StatusCode::BAD_REQUEST,
+                "text/plain",
+                format!("seconds must be between 1 and {}", MAX_CLIP_SEC),
+            )
+        }
+    };
+
+    match clips.try_send(Duration::from_secs(seconds)) {
+        Ok(()) => server::full(StatusCode::ACCEPTED, "text/plain", "recording clip"),
+        Err(mpsc::error::TrySendError::Full(_)) => server::full(
+            StatusCode::CONFLICT,
+            "text/plain",
+            "a clip is already being recorded",
+        ),
+        Err(mpsc::error::TrySendError::Closed(_)) => server::status(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Why a requested snapshot wasn't uploaded.
+pub(crate) enum SnapshotError {
+    /// Another snapshot requested from the camera hasn't been taken yet.
+    Pending,
+    /// The camera has stopped.
+    Stopped,
+    /// The camera stopped before taking it.
+    Abandoned,
+    Failed(anyhow::Error),
+}
+
+/// Asks the camera for a snapshot, returning its size once it's been uploaded.
+///
+/// The camera takes it between captures, so this waits for any capture or upload in progress.
+pub(crate) async fn request_snapshot(snapshots: &SnapshotRequests) -> Result<usize, SnapshotError> {
+    let (reply, result) = oneshot::channel();
+
+    snapshots.try_send(reply).map_err(|e| match e {
+        mpsc::error::TrySendError::Full(_) => SnapshotError::Pending,
+        mpsc::error::TrySendError::Closed(_) => SnapshotError::Stopped,
+    })?;
+
+    match result.await {
+        Ok(result) => result.map_err(SnapshotError::Failed),
+        Err(_) => Err(SnapshotError::Abandoned),
+    }
+}
+
+/// Responds once a snapshot requested from the camera has been uploaded.
+async fn snapshot(snapshots: SnapshotRequests) -> Response<Body> {
+    match request_snapshot(&snapshots).await {
+        Ok(bytes) => server::full(
+            StatusCode::OK,
+            "text/plain",
+            format!("uploaded {} byte snapshot", bytes),
+        ),
+        Err(SnapshotError::Pending) => server::full(
+            StatusCode::CONFLICT,
+            "text/plain",
+            "a snapshot is already pending",
+        ),
+        Err(SnapshotError::Stopped) => server::status(StatusCode::NOT_FOUND),
+        Err(SnapshotError::Abandoned) => server::status(StatusCode::SERVICE_UNAVAILABLE),
+        Err(SnapshotError::Failed(e)) => {
+            server::full(StatusCode::BAD_GATEWAY, "text/plain", format!("{:#}", e))
+        }
+    }
+}
+
+/// Streams each new frame as a part of a `multipart/x-mixed-replace` response.
+fn mjpeg(mut frames: LastFrame) -> Response<Body> {
+    // start with the current frame rather than waiting for the next capture.
+    frames.mark_changed();
+
+    let parts = futures_util::stream::unfold(frames, |mut frames| async move {
+        loop {
+            // the camera has stopped once the sender is dropped.
+            frames.changed().await.ok()?;
+
+            let frame = frames.borrow_and_update().clone();
+            if let Some(frame) = frame {
+                return Some((frame, frames));
+            }
+        }
+    })
+    .flat_map(|frame| {
+        let header = format!(
+            "--{BOUNDARY}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+            OutputFormat::detect(&frame).content_type(),
+            frame.len()
+        );
+
+        futures_util::stream::iter([Bytes::from(header), frame, Bytes::from_static(b"\r\n")])
+    })
+    .map(|chunk| Ok(Frame::data(chunk)));
+
+    Response::builder()
+        .header(
+            CONTENT_TYPE,
+            format!("multipart/x-mixed-replace; boundary={BOUNDARY}"),
+        )
+        .body(StreamBody::new(parts).boxed_unsync())
+        .expect("valid response")
+}
@@ -0,0 +1,166 @@
+//! Polls a printer's PrusaLink status, so frames can be captured less often while it's idle.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use reqwest::Client;
+use serde::Deserialize;
+use tracing::{debug, info, warn};
+use url::Url;
+
+use crate::{Secret, SecretUrl};
+
+pub(crate) const DEFAULT_POLL_INTERVAL_SEC: u64 = 30;
+
+const TIMEOUT_SEC: u64 = 10;
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub(crate) struct PrusaLinkConfig {
+    /// Eg: "http://192.168.0.20".
+    pub(crate) url: SecretUrl,
+    pub(crate) api_key: Option<Secret>,
+    /// How often the printer's state is checked, in seconds.
+    #[serde(default = "default_poll_interval_sec")]
+    pub(crate) poll_interval_sec: u64,
+    /// Seconds between snapshots while the printer is idle, none are taken if unset.
+    pub(crate) idle_interval_sec: Option<u64>,
+}
+
+fn default_poll_interval_sec() -> u64 {
+    DEFAULT_POLL_INTERVAL_SEC
+}
+
+impl PrusaLinkConfig {
+    pub(crate) fn validate(&self) -> Result<()> {
+        Url::parse(self.url.expose()).context("invalid prusalink url")?;
+
+        if self.poll_interval_sec == 0 {
+            bail!("prusalink poll_interval_sec must be greater than 0");
+        }
+
+        if self.idle_interval_sec == Some(0) {
+            bail!("prusalink idle_interval_sec must be greater than 0");
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct Status {
+    printer: Printer,
+}
+
+#[derive(Deserialize)]
+struct Printer {
+    state: String,
+}
+
+/// A camera's printer, polled at most every `poll_interval`.
+pub(crate) struct PrusaLink {
+    client: Arc<Client>,
+    status_url: String,
+    api_key: Option<Secret>,
+    poll_interval: Duration,
+    idle_interval: Option<Duration>,
+    /// When the state was last polled and whether the printer was printing.
+    last_poll: Option<(Instant, bool)>,
+    /// Whether the last poll failed, so that an unreachable printer is only warned about once.
+    unreachable: bool,
+}
+
+impl PrusaLink {
+    pub(crate) fn new(client: Arc<Client>, config: &PrusaLinkConfig) -> Self {
+        Self {
+            client,
+            status_url: format!(
+                "{}/api/v1/status",
+                config.url.expose().trim_end_matches('/')
+            ),
+            api_key: config.api_key.clone(),
+            poll_interval: Duration::from_secs(config.poll_interval_sec),
+            idle_interval: config.idle_interval_sec.map(Duration::from_secs),
+            last_poll: None,
+            unreachable: false,
+        }
+    }
+
+    pub(crate) fn poll_interval(&self) -> Duration {
+        self.poll_interval
+    }
+
+    /// Time between snapshots while the printer is idle, `None` to take none.
+    pub(crate) fn idle_interval(&self) -> Option<Duration> {
+        self.idle_interval
+    }
+
+    /// Whether the printer is printing or paused, polling PrusaLink if `poll_interval` has passed.
+    ///
+    /// The printer is assumed to be printing if PrusaLink can't be reached, so that a print isn't
+    /// missed.
+    pub(crate) async fn printing(&mut self, token: &str) -> bool {
+        if let Some((polled, printing)) = self.last_poll {
+            if polled.elapsed() < self.poll_interval {
+                return printing;
+            }
+        }
+
+        let printing = match self.fetch().await {
+            Ok(state) => {
+                debug!("camera {}: printer is {}", token, state);
+                if self.unreachable {
+                    info!("camera {}: prusalink reachable again", token);
+                    self.unreachable = false;
+                }
+                matches!(state.as_str(), "PRINTING" | "PAUSED" | "BUSY" | "ATTENTION")
+            }
+            Err(e) => {
+                if !self.unreachable {
+                    warn!(err=?e, "camera {}: error polling prusalink, assuming printing: {:?}", token, e);
+                    self.unreachable = true;
+                }
+                true
+            }
+        };
+
+        if self
+            .last_poll
+            .is_some_and(|(_, previous)| previous != printing)
+        {
+            info!(
+                "camera {}: printer {}",
+                token,
+                if printing { "started printing" } else { "idle" }
+            );
+        }
+
+        self.last_poll = Some((Instant::now(), printing));
+        printing
+    }
+
+    async fn fetch(&self) -> Result<String> {
+        let mut request = self
+            .client
+            .get(&self.status_url)
+            .timeout(Duration::from_secs(TIMEOUT_SEC));
+
+        if let Some(api_key) = &self.api_key {
+            request = request.header("X-Api-Key", api_key.expose());
+        }
+
+        let body = request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .context("error requesting printer status")?
+            .bytes()
+            .await
+            .context("error reading printer status")?;
+
+        let status: Status =
+            serde_json::from_slice(&body).context("error parsing printer status")?;
+
+        Ok(status.printer.state)
+    }
+}
@@ -0,0 +1,168 @@
+//! Uploads snapshots to S3 compatible object storage (eg: MinIO), signing requests with AWS
+//! signature version 4.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use jiff::Timestamp;
+use reqwest::header::CONTENT_LENGTH;
+use reqwest::Client;
+use ring::{digest, hmac};
+use serde::Deserialize;
+use url::Url;
+
+use crate::image::OutputFormat;
+use crate::throttle;
+use crate::upload::UploadLimits;
+use crate::{Secret, SecretUrl};
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub(crate) struct S3Config {
+    /// Eg: "https://s3.us-east-1.amazonaws.com" or "http://minio:9000".
+    pub(crate) endpoint: SecretUrl,
+    pub(crate) bucket: String,
+    #[serde(default = "default_region")]
+    pub(crate) region: String,
+    pub(crate) access_key: String,
+    pub(crate) secret_key: Secret,
+    /// Prepended to each object's key, eg: "printers".
+    pub(crate) prefix: Option<String>,
+}
+
+fn default_region() -> String {
+    "us-east-1".to_string()
+}
+
+/// Uploads a camera's snapshots as `<prefix>/<token>/<timestamp>.jpg` (or `.png`).
+pub(crate) struct S3Uploader {
+    client: Arc<Client>,
+    config: S3Config,
+    token: String,
+    timeout: Duration,
+    /// Shared with every camera's other uploads.
+    limits: UploadLimits,
+}
+
+impl S3Uploader {
+    pub(crate) fn new(
+        client: Arc<Client>,
+        config: S3Config,
+        token: String,
+        timeout: Duration,
+        limits: UploadLimits,
+    ) -> Self {
+        Self {
+            client,
+            config,
+            token,
+            timeout,
+            limits,
+        }
+    }
+
+    pub(crate) async fn upload(&self, frame: Bytes) -> Result<()> {
+        let now = Timestamp::now();
+        let format = OutputFormat::detect(&frame);
+
+        // utc so that keys sort chronologically, matching the local archive.
+        let name = format!(
+            "{}.{}",
+            now.strftime("%Y-%m-%dT%H-%M-%S%.3fZ"),
+            format.extension()
+        );
+
+        // path style, which MinIO and friends support without extra dns setup.
+        let mut url = Url::parse(self.config.endpoint.expose()).context("invalid s3 endpoint")?;
+        url.path_segments_mut()
+            .map_err(|()| anyhow!("invalid s3 endpoint"))?
+            .pop_if_empty()
+            .push(&self.config.bucket)
+            .extend(
+                self.config
+                    .prefix
+                    .iter()
+                    .flat_map(|prefix| prefix.split('/'))
+                    .filter(|segment| !segment.is_empty()),
+            )
+            .push(&self.token)
+            .push(&name);
+
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+
+        let amz_date = now.strftime("%Y%m%dT%H%M%SZ").to_string();
+        let date = &amz_date[..8];
+        let payload_hash = hex::encode(digest::digest(&digest::SHA256, &frame));
+
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "PUT\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            url.path(),
+            host,
+            payload_hash,
+            amz_date,
+            signed_headers,
+            payload_hash
+        );
+
+        let scope = format!("{}/{}/s3/aws4_request", date, self.config.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(digest::digest(
+                &digest::SHA256,
+                canonical_request.as_bytes()
+            ))
+        );
+
+        let signing_key = [date, &self.config.region, "s3", "aws4_request"]
+            .into_iter()
+            .fold(
+                format!("AWS4{}", self.config.secret_key.expose()).into_bytes(),
+                |key, part| sign(&key, part.as_bytes()),
+            );
+        let signature = hex::encode(sign(&signing_key, string_to_sign.as_bytes()));
+
+        let _permit = self
+            .limits
+            .permits
+            .acquire()
+            .await
+            .context("upload semaphore closed")?;
+
+        let length = frame.len();
+        let body = throttle::body(self.limits.throttle.as_ref(), frame, self.timeout)?;
+
+        self.client
+            .put(url)
+            .timeout(self.timeout)
+            .header("content-type", format.content_type())
+            .header(CONTENT_LENGTH, length)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", amz_date)
+            .header(
+                "authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                    self.config.access_key, scope, signed_headers, signature
+                ),
+            )
+            .body(body)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+fn sign(key: &[u8], data: &[u8]) -> Vec<u8> {
+    hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), data)
+        .as_ref()
+        .to_vec()
+}
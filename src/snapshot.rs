@@ -0,0 +1,56 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use bytes::Bytes;
+use reqwest::header::{HeaderValue, AUTHORIZATION};
+use reqwest::Client;
+
+use crate::CameraConfig;
+
+/// Fetches jpeg snapshots over HTTP (eg: an ONVIF snapshot uri), for when the RTSP stream fails
+/// to produce a frame.
+pub(crate) struct SnapshotFallback {
+    client: Arc<Client>,
+    url: String,
+    username: Option<String>,
+    password: Option<String>,
+    auth_header: Option<String>,
+}
+
+impl SnapshotFallback {
+    /// Returns `None` if the camera has no `snapshot_url`.
+    pub(crate) fn new(client: Arc<Client>, config: &CameraConfig) -> Option<Self> {
+        Some(Self {
+            client,
+            url: config.snapshot_url.as_ref()?.expose().to_string(),
+            username: config.username.clone(),
+            password: config
+                .password
+                .as_ref()
+                .map(|password| password.expose().to_string()),
+            auth_header: config
+                .auth_header
+                .as_ref()
+                .map(|auth_header| auth_header.expose().to_string()),
+        })
+    }
+
+    pub(crate) async fn fetch(&self) -> Result<Bytes> {
+        let mut request = self.client.get(&self.url);
+
+        if let Some(auth_header) = &self.auth_header {
+            let mut value = HeaderValue::from_str(auth_header)?;
+            value.set_sensitive(true);
+            request = request.header(AUTHORIZATION, value);
+        } else if self.username.is_some() || self.password.is_some() {
+            request = request.basic_auth(
+                self.username.as_deref().unwrap_or_default(),
+                self.password.as_ref(),
+            );
+        }
+
+        let response = request.send().await?.error_for_status()?;
+
+        Ok(response.bytes().await?)
+    }
+}
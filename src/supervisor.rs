@@ -0,0 +1,244 @@
+//! Runs a task per camera, restarting only the cameras affected when the config is reloaded.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use retina::client::SessionGroup;
+use tokio::sync::watch;
+use tokio::task::{JoinError, JoinSet};
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::camera::Camera;
+use crate::health;
+use crate::mqtt::Mqtt;
+use crate::preview::{Preview, SnapshotRequests};
+use crate::upload::UploadLimits;
+use crate::{build_client, CameraConfig, Config, SHUTDOWN_TIMEOUT_SEC};
+
+pub(crate) struct Supervisor {
+    config: Config,
+    client: Arc<reqwest::Client>,
+    session_group: Arc<SessionGroup>,
+    preview: Option<Preview>,
+    upload_limits: UploadLimits,
+    mqtt: Option<Mqtt>,
+    /// By token.
+    running: HashMap<String, Running>,
+    /// Cameras paused through the control socket, which stay paused when restarted.
+    paused: HashSet<String>,
+    tasks: JoinSet<()>,
+}
+
+struct Running {
+    /// The config the camera was started with.
+    config: CameraConfig,
+    shutdown: watch::Sender<bool>,
+    paused: watch::Sender<bool>,
+    snapshots: SnapshotRequests,
+}
+
+impl Supervisor {
+    pub(crate) fn new(
+        config: Config,
+        client: Arc<reqwest::Client>,
+        session_group: Arc<SessionGroup>,
+        preview: Option<Preview>,
+        upload_limits: UploadLimits,
+        mqtt: Option<Mqtt>,
+    ) -> Self {
+        Self {
+            config,
+            client,
+            session_group,
+            preview,
+            upload_limits,
+            mqtt,
+            running: HashMap::new(),
+            paused: HashSet::new(),
+            tasks: JoinSet::new(),
+        }
+    }
+
+    /// Starts capturing from `camera`, which was built from `config`.
+    pub(crate) fn start(&mut self, camera: Camera, config: CameraConfig) {
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let (paused, paused_rx) = watch::channel(self.paused.contains(&config.token));
+        let snapshots = camera.snapshots.clone();
+
+        let span = info_span!("camera", token = %camera.token);
+        self.tasks.spawn(
+            camera
+                .run(self.config.snapshot_interval, shutdown_rx, paused_rx)
+                .instrument(span),
+        );
+
+        self.running.insert(
+            config.token.clone(),
+            Running {
+                config,
+                shutdown,
+                paused,
+                snapshots,
+            },
+        );
+    }
+
+    /// Pauses or resumes the camera with `token`, returning false if it isn't running.
+    pub(crate) fn set_paused(&mut self, token: &str, paused: bool) -> bool {
+        let Some(running) = self.running.get(token) else {
+            return false;
+        };
+
+        running.paused.send_replace(paused);
+        if paused {
+            self.paused.insert(token.to_string());
+        } else {
+            self.paused.remove(token);
+        }
+
+        true
+    }
+
+    /// Requests snapshots from the camera with `token`, `None` if it isn't running.
+    pub(crate) fn snapshots(&self, token: &str) -> Option<SnapshotRequests> {
+        self.running
+            .get(token)
+            .map(|running| running.snapshots.clone())
+    }
+
+    /// Stops the camera with `token`, letting an in-progress upload finish.
+    fn stop(&mut self, token: &str) {
+        if let Some(running) = self.running.remove(token) {
+            running.shutdown.send_replace(true);
+        }
+
+        if let Some(preview) = &self.preview {
+            preview.remove(token);
+        }
+
+        health::unregister_camera(token);
+    }
+
+    /// Applies a reloaded config: removed, disabled and changed cameras are stopped, then new and
+    /// changed cameras are started. Every camera is restarted if the settings they share changed.
+    ///
+    /// A config without any enabled cameras stops them all, leaving the supervisor idle until a
+    /// later reload adds one.
+    pub(crate) fn reload(&mut self, config: Config) {
+        if config.preview_addr != self.config.preview_addr
+            || config.metrics_addr != self.config.metrics_addr
+            || config.health_addr != self.config.health_addr
+            || config.mqtt != self.config.mqtt
+            || config.control_socket != self.config.control_socket
+        {
+            warn!(
+                "changes to preview_addr, metrics_addr, health_addr, mqtt and control_socket take effect after a restart"
+            );
+        }
+
+        let settings_changed = shared_settings(&config) != shared_settings(&self.config);
+
+        if config.proxy != self.config.proxy || config.no_proxy != self.config.no_proxy {
+            match build_client(&config) {
+                Ok(client) => self.client = Arc::new(client),
+                Err(e) => error!(err=?e, "error rebuilding http client: {:?}", e),
+            }
+        }
+
+        if config.max_concurrent_uploads != self.config.max_concurrent_uploads
+            || config.max_upload_bytes_per_sec != self.config.max_upload_bytes_per_sec
+        {
+            self.upload_limits = UploadLimits::new(&config);
+        }
+
+        let stale = self
+            .running
+            .iter()
+            .filter(|(token, running)| {
+                settings_changed
+                    || !config
+                        .cameras
+                        .iter()
+                        .any(|camera| &camera.token == *token && camera == &running.config)
+            })
+            .map(|(token, _)| token.clone())
+            .collect::<Vec<_>>();
+
+        for token in stale {
+            info!("stopping camera {}", token);
+            self.stop(&token);
+        }
+
+        self.config = config;
+        health::set_configured(&self.config.cameras);
+
+        for camera in self.config.cameras.clone() {
+            if !camera.enabled() || self.running.contains_key(&camera.token) {
+                continue;
+            }
+
+            match Camera::new(
+                &self.config,
+                &camera,
+                &self.client,
+                &self.session_group,
+                self.preview.as_ref(),
+                &self.upload_limits,
+                self.mqtt.as_ref(),
+            ) {
+                Ok(built) => {
+                    info!("starting camera {}", camera.token);
+                    self.start(built, camera);
+                }
+                Err(e) => error!(err=?e, "error starting camera {}: {:?}", camera.token, e),
+            }
+        }
+
+        // unlike at startup this isn't fatal, eg: while the config is being edited.
+        if self.running.is_empty() {
+            warn!("no cameras are running after reloading the config, idling until one is added");
+        }
+    }
+
+    /// Waits for the next camera task to finish, `None` if there are none.
+    pub(crate) async fn join_next(&mut self) -> Option<Result<(), JoinError>> {
+        self.tasks.join_next().await
+    }
+
+    /// Stops every camera, aborting those that don't stop within the shutdown timeout.
+    pub(crate) async fn shutdown(mut self) {
+        for running in self.running.values() {
+            running.shutdown.send_replace(true);
+        }
+
+        let timeout = Duration::from_secs(SHUTDOWN_TIMEOUT_SEC);
+
+        let stopped = tokio::time::timeout(timeout, async {
+            while let Some(result) = self.tasks.join_next().await {
+                if let Err(e) = result {
+                    error!(err=?e, "camera task failed: {:?}", e)
+                }
+            }
+        })
+        .await;
+
+        if stopped.is_err() {
+            warn!("timeout waiting for cameras to stop, aborting");
+            self.tasks.shutdown().await;
+        }
+    }
+}
+
+/// The settings every camera is built from, ignoring the cameras themselves and the servers
+/// (which aren't restarted).
+fn shared_settings(config: &Config) -> Config {
+    Config {
+        preview_addr: None,
+        metrics_addr: None,
+        health_addr: None,
+        mqtt: None,
+        cameras: Vec::new(),
+        ..config.clone()
+    }
+}
@@ -0,0 +1,87 @@
+//! Notifies systemd of the service's state, for `Type=notify` units, and pings its watchdog if
+//! `WatchdogSec` is set. Does nothing when not run by systemd.
+
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+use tracing::{debug, warn};
+
+/// Tells systemd the service has started, once the camera tasks are running and the servers are
+/// listening. Cameras may still be offline, and paused or idle cameras may never capture.
+pub(crate) fn ready() {
+    notify("READY=1");
+}
+
+pub(crate) fn stopping() {
+    notify("STOPPING=1");
+}
+
+/// Pings the watchdog at half the interval systemd asks for, if it asks for one.
+pub(crate) fn spawn_watchdog() {
+    let Some(interval) = watchdog_interval() else {
+        return;
+    };
+
+    debug!("pinging the systemd watchdog every {:?}", interval);
+
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            notify("WATCHDOG=1");
+        }
+    });
+}
+
+/// Half of `WATCHDOG_USEC`, if it's set for this process.
+fn watchdog_interval() -> Option<Duration> {
+    // set for the main process only, which a child wouldn't be.
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    Some(Duration::from_micros(usec / 2)).filter(|interval| !interval.is_zero())
+}
+
+fn notify(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+
+    if let Err(e) = send(&path, state) {
+        warn!(err=?e, "error notifying systemd of {}: {:?}", state, e);
+    }
+}
+
+fn send(path: &OsStr, state: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+
+    // a leading @ is a socket in the abstract namespace.
+    match path.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+
+            let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &address)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "abstract sockets are only supported on linux",
+            ))
+        }
+        None => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+
+    Ok(())
+}
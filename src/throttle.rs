@@ -0,0 +1,171 @@
+//! Streams upload bodies, optionally limiting how fast snapshots are uploaded across all cameras,
+//! eg: on a metered connection.
+
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use http_body_util::StreamBody;
+use hyper::body::{Frame, SizeHint};
+use reqwest::Body;
+
+use crate::metrics;
+
+/// Bodies are sent in chunks of this size, so that throttled uploads from several cameras
+/// interleave.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// A token bucket holding up to a second of bandwidth.
+pub(crate) struct Throttle {
+    bytes_per_sec: u64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    /// Negative while uploads are waiting for bandwidth they've already taken.
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, bytes_per_sec: u64) {
+        let now = Instant::now();
+        let refilled = now.duration_since(self.updated).as_secs_f64() * bytes_per_sec as f64;
+
+        self.tokens = (self.tokens + refilled).min(bytes_per_sec as f64);
+        self.updated = now;
+    }
+}
+
+impl Throttle {
+    pub(crate) fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec,
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_sec as f64,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// How long until `bytes` could be sent, if nothing else is sent first.
+    fn wait(&self, bytes: usize) -> Duration {
+        let mut bucket = self.bucket.lock().expect("lock poisoned");
+        bucket.refill(self.bytes_per_sec);
+
+        let deficit = (bytes as f64 - bucket.tokens).max(0.0);
+        Duration::from_secs_f64(deficit / self.bytes_per_sec as f64)
+    }
+
+    /// Takes `bytes` from the bucket, waiting until it has refilled enough to cover them.
+    async fn take(&self, bytes: usize) {
+        let deficit = {
+            let mut bucket = self.bucket.lock().expect("lock poisoned");
+            bucket.refill(self.bytes_per_sec);
+            bucket.tokens -= bytes as f64;
+            -bucket.tokens
+        };
+
+        if deficit > 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(deficit / self.bytes_per_sec as f64)).await;
+        }
+    }
+}
+
+/// The body to upload `frame` with, streamed in chunks sliced from the frame, no faster than
+/// `throttle` allows if set.
+///
+/// The body reports its exact length, so it's sent with a `Content-Length` rather than chunked,
+/// which Prusa Connect has been reported to reject. Callers set the header too, to not depend on
+/// that.
+///
+/// Fails without sending anything if so much bandwidth has been used that the frame couldn't be
+/// sent within `timeout`, dropping it rather than falling further behind.
+pub(crate) fn body(
+    throttle: Option<&Arc<Throttle>>,
+    frame: Bytes,
+    timeout: Duration,
+) -> Result<Body> {
+    if let Some(throttle) = throttle {
+        let wait = throttle.wait(frame.len());
+        if wait >= timeout {
+            bail!(
+                "upload bandwidth exhausted, dropping frame that would take {:?} to send",
+                wait
+            );
+        }
+    }
+
+    let length = frame.len() as u64;
+    let chunks = futures_util::stream::unfold(
+        (frame, throttle.cloned()),
+        |(mut rest, throttle)| async move {
+            if rest.is_empty() {
+                return None;
+            }
+
+            let chunk = rest.split_to(rest.len().min(CHUNK_SIZE));
+            if let Some(throttle) = &throttle {
+                throttle.take(chunk.len()).await;
+            }
+            metrics::record_upload_bytes(chunk.len());
+
+            Some((Ok::<_, Infallible>(Frame::data(chunk)), (rest, throttle)))
+        },
+    );
+
+    Ok(Body::wrap(ExactLength {
+        inner: StreamBody::new(Box::pin(chunks)),
+        length,
+    }))
+}
+
+/// A body of `length` bytes.
+struct ExactLength<B> {
+    inner: B,
+    length: u64,
+}
+
+impl<B: hyper::body::Body + Unpin> hyper::body::Body for ExactLength<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::with_exact(self.length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn body_reports_frame_length() {
+        // several chunks, so the length isn't known from a single one.
+        let frame = Bytes::from(vec![0; 3 * CHUNK_SIZE + 1]);
+        let throttle = Arc::new(Throttle::new(1024 * 1024));
+
+        for throttle in [None, Some(&throttle)] {
+            let body = body(throttle, frame.clone(), Duration::from_secs(10)).expect("body");
+            assert_eq!(
+                hyper::body::Body::size_hint(&body).exact(),
+                Some(frame.len() as u64)
+            );
+        }
+    }
+}
@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use bytes::Bytes;
+use rand::Rng;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_LENGTH, RETRY_AFTER};
+use reqwest::{Client, Response, StatusCode};
+use serde::Deserialize;
+use tokio::sync::Semaphore;
+use tracing::warn;
+
+use crate::image::OutputFormat;
+use crate::throttle::{self, Throttle};
+use crate::{redact_url, Config, Secret, SecretUrl};
+
+pub(crate) const DEFAULT_UPLOAD_URL: &str = "https://webcam.connect.prusa3d.com/c/snapshot";
+pub(crate) const DEFAULT_UPLOAD_MAX_RETRIES: u32 = 3;
+pub(crate) const DEFAULT_UPLOAD_TIMEOUT_SEC: u64 = 30;
+pub(crate) const DEFAULT_MAX_CONCURRENT_UPLOADS: usize = 4;
+
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+/// The longest a `Retry-After` header can pause uploads for.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60 * 60);
+/// Headers every upload sets itself, which `upload_headers` can't, lowercase. The fingerprint is
+/// configured with `fingerprint` instead.
+const RESERVED_HEADERS: [&str; 4] = ["content-type", "content-length", "token", "fingerprint"];
+
+/// Another endpoint a camera's frames are uploaded to, as well as `upload_url`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub(crate) struct UploadTarget {
+    pub(crate) url: SecretUrl,
+    /// Added to the camera's headers, replacing any with the same name.
+    #[serde(default)]
+    pub(crate) headers: HashMap<String, Secret>,
+}
+
+/// Limits shared by every camera's uploads.
+#[derive(Clone)]
+pub(crate) struct UploadLimits {
+    /// Limits how many uploads are in flight at once.
+    pub(crate) permits: Arc<Semaphore>,
+    /// Limits how fast uploads are sent, unlimited if unset.
+    pub(crate) throttle: Option<Arc<Throttle>>,
+}
+
+impl UploadLimits {
+    pub(crate) fn new(config: &Config) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(config.max_concurrent_uploads)),
+            throttle: config
+                .max_upload_bytes_per_sec
+                .map(|bytes_per_sec| Arc::new(Throttle::new(bytes_per_sec))),
+        }
+    }
+}
+
+/// Uploads snapshots from a single camera to Prusa Connect (or a compatible endpoint).
+pub(crate) struct Uploader {
+    client: Arc<Client>,
+    url: String,
+    token: String,
+    max_retries: u32,
+    /// How long each upload attempt may take.
+    timeout: Duration,
+    /// Sent with every upload: the `Fingerprint` and any configured `upload_headers`.
+    headers: HeaderMap,
+    /// Shared by all cameras.
+    limits: UploadLimits,
+    /// Set from the `Retry-After` header of a 429 response.
+    paused_until: Mutex<Option<Instant>>,
+}
+
+impl Uploader {
+    pub(crate) fn new(
+        client: Arc<Client>,
+        url: String,
+        token: String,
+        max_retries: u32,
+        timeout: Duration,
+        headers: HeaderMap,
+        limits: UploadLimits,
+    ) -> Self {
+        Self {
+            client,
+            url,
+            token,
+            max_retries,
+            timeout,
+            headers,
+            limits,
+            paused_until: Mutex::new(None),
+        }
+    }
+
+    /// Where frames are uploaded to, with any password redacted, to label metrics and logs.
+    pub(crate) fn target(&self) -> String {
+        redact_url(&self.url)
+    }
+
+    /// Uploads a frame, retrying connection errors, timeouts and 5xx/429 responses with
+    /// exponential backoff.
+    ///
+    /// A 429 response with a `Retry-After` header instead fails the upload, and uploads fail
+    /// without being attempted until the requested time has passed.
+    pub(crate) async fn upload(&self, frame: Bytes) -> Result<()> {
+        if let Some(remaining) = self.paused_for() {
+            bail!(
+                "uploads paused for another {:?} after being rate limited",
+                remaining
+            );
+        }
+
+        let content_type = OutputFormat::detect(&frame).content_type();
+        let mut attempt = 0;
+
+        loop {
+            // held for the attempt only, not while backing off.
+            let _permit = self
+                .limits
+                .permits
+                .acquire()
+                .await
+                .context("upload semaphore closed")?;
+
+            let body = throttle::body(self.limits.throttle.as_ref(), frame.clone(), self.timeout)?;
+
+            let send = self
+                .client
+                .put(&self.url)
+                .timeout(self.timeout)
+                .headers(self.headers.clone())
+                .header("content-type", content_type)
+                // explicit, as prusa connect may reject chunked uploads.
+                .header(CONTENT_LENGTH, frame.len())
+                .header("Token", &self.token)
+                .body(body)
+                .send();
+
+            // reqwest's timeout should fire first, this guards against it not covering a stall.
+            let (retryable, result) = match tokio::time::timeout(self.timeout, send).await {
+                Ok(Ok(response)) => {
+                    if let Some(retry_after) = rate_limited(&response) {
+                        warn!(
+                            "camera {} rate limited, pausing uploads for {:?}",
+                            self.token, retry_after
+                        );
+                        *self.paused_until.lock().expect("lock poisoned") =
+                            Some(Instant::now() + retry_after);
+                        bail!("rate limited, retry after {:?}", retry_after);
+                    }
+
+                    (
+                        response.status().is_server_error()
+                            || response.status() == StatusCode::TOO_MANY_REQUESTS,
+                        response.error_for_status().map(drop).map_err(Into::into),
+                    )
+                }
+                Ok(Err(e)) if e.is_timeout() => (true, Err(self.timed_out())),
+                Ok(Err(e)) => (e.is_connect(), Err(e.into())),
+                Err(_) => (true, Err(self.timed_out())),
+            };
+
+            match result {
+                Ok(()) => return Ok(()),
+                Err(e) if retryable && attempt < self.max_retries => {
+                    let delay = backoff(attempt);
+                    warn!(
+                        "upload attempt {} for camera {} failed, retrying in {:?}: {:#}",
+                        attempt + 1,
+                        self.token,
+                        delay,
+                        e
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// How much longer uploads are paused for, if they are.
+    fn paused_for(&self) -> Option<Duration> {
+        let paused_until = (*self.paused_until.lock().expect("lock poisoned"))?;
+        paused_until
+            .checked_duration_since(Instant::now())
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    fn timed_out(&self) -> anyhow::Error {
+        warn!(
+            "upload timed out after {:?} for camera {}",
+            self.timeout, self.token
+        );
+        anyhow!("upload timed out after {:?}", self.timeout)
+    }
+}
+
+/// The delay requested by a 429 response's `Retry-After` header, either in seconds or as an HTTP
+/// date, capped at `MAX_RETRY_AFTER`.
+fn rate_limited(response: &Response) -> Option<Duration> {
+    if response.status() != StatusCode::TOO_MANY_REQUESTS {
+        return None;
+    }
+
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    let delay = match value.parse::<u64>() {
+        Ok(seconds) => Duration::from_secs(seconds),
+        Err(_) => {
+            let at = jiff::fmt::rfc2822::DateTimeParser::new()
+                .parse_timestamp(value)
+                .ok()?;
+            Duration::try_from(at.duration_since(jiff::Timestamp::now())).unwrap_or(Duration::ZERO)
+        }
+    };
+
+    Some(delay.min(MAX_RETRY_AFTER))
+}
+
+/// Parses the `upload_headers` config into a header map, rejecting the headers uploads set
+/// themselves.
+pub(crate) fn parse_headers(headers: &HashMap<String, Secret>) -> Result<HeaderMap> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .with_context(|| format!("invalid header name {:?}", name))?;
+            if RESERVED_HEADERS.contains(&name.as_str()) {
+                bail!(
+                    "header {} is set by every upload and can't be configured",
+                    name
+                );
+            }
+            let mut value = HeaderValue::from_str(value.expose())
+                .with_context(|| format!("invalid value for header {}", name))?;
+            value.set_sensitive(true);
+
+            Ok((name, value))
+        })
+        .collect()
+}
+
+/// Exponential backoff with up to 50% random jitter, capped at `RETRY_MAX_DELAY`.
+fn backoff(attempt: u32) -> Duration {
+    let delay = RETRY_BASE_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(RETRY_MAX_DELAY);
+
+    delay.mul_f64(1.0 + rand::thread_rng().gen_range(0.0..0.5))
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::BodyExt;
+    use hyper::StatusCode;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::server;
+
+    #[tokio::test]
+    async fn upload_sends_content_length_of_frame() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let url = format!("http://{}/c/snapshot", listener.local_addr().expect("addr"));
+
+        let (requests, mut received) = mpsc::unbounded_channel();
+        tokio::spawn(server::serve(listener, move |request| {
+            let requests = requests.clone();
+            async move {
+                let headers = request.headers().clone();
+                let body = request.into_body().collect().await.expect("body");
+                let _ = requests.send((headers, body.to_bytes()));
+                server::status(StatusCode::NO_CONTENT)
+            }
+        }));
+
+        // a jpeg streamed in several chunks.
+        let mut frame = vec![0xff, 0xd8];
+        frame.resize(40 * 1024, 0);
+        let frame = Bytes::from(frame);
+
+        let client = Client::builder().no_proxy().build().expect("client");
+        let limits = UploadLimits {
+            permits: Arc::new(Semaphore::new(1)),
+            throttle: Some(Arc::new(Throttle::new(10 * 1024 * 1024))),
+        };
+        let uploader = Uploader::new(
+            Arc::new(client),
+            url,
+            "token".to_string(),
+            0,
+            Duration::from_secs(10),
+            HeaderMap::new(),
+            limits,
+        );
+
+        uploader.upload(frame.clone()).await.expect("uploaded");
+
+        let (headers, body) = received.recv().await.expect("request");
+        assert_eq!(
+            headers.get(CONTENT_LENGTH).map(HeaderValue::as_bytes),
+            Some(frame.len().to_string().as_bytes())
+        );
+        assert!(headers.get("transfer-encoding").is_none());
+        assert_eq!(body, frame);
+    }
+
+    #[test]
+    fn parse_headers_rejects_headers_uploads_set() {
+        for name in ["Token", "Content-Type", "content-length", "Fingerprint"] {
+            let headers = HashMap::from([(name.to_string(), Secret("value".to_string()))]);
+            assert!(parse_headers(&headers).is_err(), "{} was accepted", name);
+        }
+
+        let headers = HashMap::from([("Authorization".to_string(), Secret("value".to_string()))]);
+        assert_eq!(parse_headers(&headers).expect("parse").len(), 1);
+    }
+}
@@ -0,0 +1,116 @@
+//! Notifies a webhook when a camera keeps failing, and again once it recovers.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use reqwest::Client;
+use serde::Serialize;
+use tracing::{info, warn};
+
+pub(crate) const DEFAULT_FAILURE_THRESHOLD: u32 = 3;
+
+const TIMEOUT_SEC: u64 = 10;
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum FailureKind {
+    /// Neither the stream nor the fallback snapshot produced a frame.
+    Capture,
+    Upload,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Event {
+    Failure,
+    Recovered,
+}
+
+#[derive(Serialize)]
+struct Notification<'a> {
+    token: &'a str,
+    event: Event,
+    /// The kind of the most recent failure.
+    error_kind: Option<FailureKind>,
+    consecutive_failures: u32,
+}
+
+/// Counts a camera's consecutive failures, posting to the webhook once they reach the threshold
+/// and once the camera succeeds again. Failures beyond the threshold aren't re-posted.
+pub(crate) struct FailureAlerts {
+    client: Arc<Client>,
+    url: String,
+    threshold: u32,
+    consecutive: u32,
+}
+
+impl FailureAlerts {
+    pub(crate) fn new(client: Arc<Client>, url: String, threshold: u32) -> Self {
+        Self {
+            client,
+            url,
+            threshold,
+            consecutive: 0,
+        }
+    }
+
+    /// Records the outcome of a capture and upload, `None` if it succeeded.
+    pub(crate) fn record(&mut self, token: &str, failure: Option<FailureKind>) {
+        let event = match failure {
+            Some(_) => {
+                self.consecutive += 1;
+                (self.consecutive == self.threshold).then_some(Event::Failure)
+            }
+            None => {
+                let recovered = self.consecutive >= self.threshold;
+                self.consecutive = 0;
+                recovered.then_some(Event::Recovered)
+            }
+        };
+
+        let Some(event) = event else {
+            return;
+        };
+
+        let notification = Notification {
+            token,
+            event,
+            error_kind: failure,
+            consecutive_failures: self.consecutive,
+        };
+
+        let body = match serde_json::to_vec(&notification) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!(err=?e, "error encoding webhook notification: {:?}", e);
+                return;
+            }
+        };
+
+        info!("notifying failure_webhook for camera {}", token);
+
+        // don't hold up the next capture on the webhook.
+        let client = self.client.clone();
+        let url = self.url.clone();
+        tokio::spawn(async move {
+            if let Err(e) = post(&client, &url, body).await {
+                warn!(err=?e, "error notifying failure_webhook: {:?}", e);
+            }
+        });
+    }
+}
+
+async fn post(client: &Client, url: &str, body: Vec<u8>) -> Result<()> {
+    client
+        .post(url)
+        .timeout(Duration::from_secs(TIMEOUT_SEC))
+        .header("content-type", "application/json")
+        .body(body)
+        .send()
+        .await
+        .context("error sending request")?
+        .error_for_status()?;
+
+    Ok(())
+}